@@ -1,14 +1,22 @@
-use std::assert_eq;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::{self, Write};
 
 use bevy::prelude::*;
+#[cfg(test)]
+use crate::bvh_test_fixtures::*;
 
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
 pub struct AABB {
     min: Vec3,
     max: Vec3,
     center: Vec3
 }
 
+// Boxes that overlap less than this on an axis count as touching, see AABB::sweep
+pub const SWEEP_TOLERANCE: f32 = 1e-4;
+
 impl AABB {
     pub fn new(min: Vec3, max: Vec3) -> AABB {
         AABB {
@@ -18,7 +26,7 @@ impl AABB {
         }
     }
 
-    fn outer(a: &AABB, b: &AABB) -> AABB {
+    pub fn outer(a: &AABB, b: &AABB) -> AABB {
         AABB::new(
             a.min.min(b.min),
             a.max.max(b.max),
@@ -37,7 +45,7 @@ impl AABB {
     }
 
 
-    fn distance(&self, point: &Vec3) -> f32 {
+    pub fn distance(&self, point: &Vec3) -> f32 {
         // Returns negative values if point is within bounding box
         if self.contains(point) { 
             let d_x = (point.x - self.min.x).min(self.max.x - point.x);
@@ -46,15 +54,191 @@ impl AABB {
             return -1.0 * d_x.min(d_y).min(d_z); 
         } else {
             let d_x = (self.min.x - point.x).max(0.0).max(point.x - self.max.x);
-            let d_y = (self.min.y - point.y).abs().min((point.y - self.max.y).abs());
-            let d_z = (self.min.z - point.z).abs().min((point.z - self.max.z).abs());
+            let d_y = (self.min.y - point.y).max(0.0).max(point.y - self.max.y);
+            let d_z = (self.min.z - point.z).max(0.0).max(point.z - self.max.z);
             return  (d_x.powi(2) + d_y.powi(2) + d_z.powi(2)).sqrt();
         }
     }
     
+    pub fn min(&self) -> Vec3 {
+        self.min
+    }
+
+    pub fn max(&self) -> Vec3 {
+        self.max
+    }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) / 2.0
+    }
+
     pub fn translated(&self, translation: &Vec3) -> AABB {
         AABB::new(self.min + *translation, self.max + *translation)
     }
+
+    // Smallest AABB around the rotated, scaled and translated box (Arvo's method).
+    // Each half extent of the new box is the sum of the absolute
+    // contributions of the old half extents along that axis
+    pub fn transformed(&self, transform: &Transform) -> AABB {
+        let matrix = Mat3::from_quat(transform.rotation) * Mat3::from_diagonal(transform.scale);
+        let absolute = Mat3::from_cols(matrix.x_axis.abs(), matrix.y_axis.abs(), matrix.z_axis.abs());
+        let center = matrix * self.center + transform.translation;
+        let half_extents = absolute * self.half_extents();
+        AABB::new(center - half_extents, center + half_extents)
+    }
+
+    pub fn expanded(&self, margin: f32) -> AABB {
+        AABB::new(self.min - Vec3::splat(margin), self.max + Vec3::splat(margin))
+    }
+
+    pub fn contains_aabb(&self, other: &AABB) -> bool {
+        self.min.cmple(other.min).all() && self.max.cmpge(other.max).all()
+    }
+
+    pub fn intersects(&self, other: &AABB) -> bool {
+        self.min.cmple(other.max).all() && self.max.cmpge(other.min).all()
+    }
+
+    pub fn intersects_sphere(&self, center: &Vec3, radius: f32) -> bool {
+        self.distance(center) <= radius
+    }
+
+    // Planes are given as (normal, d) with normal.dot(p) + d >= 0 for points
+    // on the inside, the same convention bevy uses for its Frustum.
+    // Conservative: boxes close to a corner of the frustum can be reported
+    // as intersecting even though they are outside
+    pub fn intersects_frustum(&self, planes: &[Vec4]) -> bool {
+        for plane in planes {
+            let normal = plane.truncate();
+            // The corner that lies furthest along the normal
+            let positive_vertex = Vec3::select(normal.cmpge(Vec3::ZERO), self.max, self.min);
+            if normal.dot(positive_vertex) + plane.w < 0.0 {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Slab test. Returns the ray parameter t at which the ray
+    // origin + t * direction enters the box, 0 if the origin is inside
+    pub fn ray_intersect(&self, origin: &Vec3, direction: &Vec3) -> Option<f32> {
+        let inverse_direction = direction.recip();
+        let t_1 = (self.min - *origin) * inverse_direction;
+        let t_2 = (self.max - *origin) * inverse_direction;
+        let t_near = t_1.min(t_2).max_element().max(0.0);
+        let t_far = t_1.max(t_2).min_element();
+        if t_far < t_near {
+            return None;
+        }
+        Some(t_near)
+    }
+
+    // Time of impact of this box moving by displacement against other, as a
+    // fraction of displacement, together with the normal of the face of other
+    // that gets hit. None if they don't touch during the motion, if they move
+    // apart or if they already overlap deeper than SWEEP_TOLERANCE on every
+    // axis. Touching boxes that move into each other hit at 0, so a body that
+    // was stopped at an obstacle stays stopped in the next step
+    pub fn sweep(&self, displacement: &Vec3, other: &AABB) -> Option<(f32, Vec3)> {
+        // How far this box has to move on each axis to start and to stop overlapping other
+        let start = other.min - self.max;
+        let end = other.max - self.min;
+        let mut t_near = f32::NEG_INFINITY;
+        let mut t_far = f32::INFINITY;
+        let mut axis = 0;
+        for i in 0..3 {
+            let d = displacement[i];
+            if d == 0.0 {
+                // Never enters or leaves the slab of this axis
+                if start[i] > 0.0 || end[i] < 0.0 {
+                    return None;
+                }
+                continue;
+            }
+            // Moving away from the face it touches, or from other altogether
+            if (d > 0.0 && end[i] <= SWEEP_TOLERANCE) || (d < 0.0 && start[i] >= -SWEEP_TOLERANCE) {
+                return None;
+            }
+            let (enter, leave) = if d > 0.0 {
+                (start[i] / d, end[i] / d)
+            } else {
+                (end[i] / d, start[i] / d)
+            };
+            t_far = t_far.min(leave);
+            // Already inside this slab, e.g. sliding along a floor
+            if (-start[i]).min(end[i]) > SWEEP_TOLERANCE {
+                continue;
+            }
+            // The slab that is entered last is the face that gets hit
+            if enter > t_near {
+                t_near = enter;
+                axis = i;
+            }
+        }
+        if t_near == f32::NEG_INFINITY || t_near > t_far || t_near > 1.0 || t_far < 0.0 {
+            return None;
+        }
+        let mut normal = Vec3::ZERO;
+        normal[axis] = -displacement[axis].signum();
+        Some((t_near.max(0.0), normal))
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        2.0 * (d.x * d.y + d.y * d.z + d.z * d.x)
+    }
+
+    pub fn volume(&self) -> f32 {
+        let d = self.max - self.min;
+        d.x * d.y * d.z
+    }
+
+    // The overlapping region of both boxes, None if they are disjoint
+    pub fn intersection(&self, other: &AABB) -> Option<AABB> {
+        if !self.intersects(other) {
+            return None;
+        }
+        Some(AABB::new(self.min.max(other.min), self.max.min(other.max)))
+    }
+
+    // Corner i has its x, y and z at max if bit 0, 1 and 2 of i are set
+    pub fn corners(&self) -> [Vec3; 8] {
+        let mut corners = [Vec3::ZERO; 8];
+        for (i, corner) in corners.iter_mut().enumerate() {
+            *corner = Vec3::new(
+                if i & 1 == 0 { self.min.x } else { self.max.x },
+                if i & 2 == 0 { self.min.y } else { self.max.y },
+                if i & 4 == 0 { self.min.z } else { self.max.z },
+            );
+        }
+        corners
+    }
+}
+
+#[test]
+fn test_aabb_transformed() {
+    let bbox = AABB::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+    let transform = Transform::from_translation(Vec3::new(1.0, 2.0, 3.0));
+    assert_eq!(bbox.transformed(&transform), bbox.translated(&Vec3::new(1.0, 2.0, 3.0)));
+
+    // 45 degrees around z widens the box in x and y
+    let transform = Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4));
+    let rotated = bbox.transformed(&transform);
+    let expected = Vec3::new(2f32.sqrt(), 2f32.sqrt(), 1.0);
+    assert!((rotated.max() - expected).abs().max_element() < 1e-5);
+    assert!((rotated.min() + expected).abs().max_element() < 1e-5);
+
+    // scale is applied before rotation, an off-center box moves with it
+    let bbox = AABB::new(Vec3::new(1.0, 0.0, 0.0), Vec3::new(2.0, 1.0, 1.0));
+    let transform = Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2))
+        .with_scale(Vec3::new(2.0, 1.0, 1.0));
+    let transformed = bbox.transformed(&transform);
+    assert!((transformed.min() - Vec3::new(-1.0, 2.0, 0.0)).abs().max_element() < 1e-5);
+    assert!((transformed.max() - Vec3::new(0.0, 4.0, 1.0)).abs().max_element() < 1e-5);
 }
 
 #[test]
@@ -67,151 +251,803 @@ fn test_aabb_distance() {
     assert_eq!(bbox.distance(&Vec3::splat(2.0)), 0.0);
     assert_eq!(bbox.distance(&Vec3::splat(1.5)), -0.5);
     assert_eq!(bbox.distance(&Vec3::splat(1.25)), -0.25);
+    assert_eq!(bbox.distance(&Vec3::new(1.5, 1.5, 3.0)), 1.0);
 }
 
-#[derive(Debug)]
-pub struct BVHNode<T: Clone> {
-    data: Option<T>,
-    bbox: AABB,
-    left: Option<Box<BVHNode<T>>>,
-    right: Option<Box<BVHNode<T>>>,
+// Oriented bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OBB {
+    center: Vec3,
+    // Unit length axes of the box, as columns
+    axes: Mat3,
+    half_extents: Vec3,
 }
 
-impl<T> BVHNode<T>
-where T: Clone {
-    fn new(data: T, bbox: AABB) -> BVHNode<T> {
-        BVHNode {
-            data: Some(data),
-            bbox: bbox,
-            left: None,
-            right: None,
-        }
-    }
-    pub fn create(mut data_and_boxes: Vec<(T, AABB)>) -> Option<BVHNode<T>> {
-        match data_and_boxes.len() {
-            0 => { 
-                // This should not happen
-                assert!(false);
-                return None;
+impl OBB {
+    pub fn new(center: Vec3, rotation: Quat, half_extents: Vec3) -> OBB {
+        OBB {
+            center,
+            axes: Mat3::from_quat(rotation),
+            half_extents,
+        }
+    }
+
+    // The local box aabb, placed in the world by transform
+    pub fn from_aabb(aabb: &AABB, transform: &Transform) -> OBB {
+        let center = transform.translation + transform.rotation * (transform.scale * aabb.center);
+        OBB::new(center, transform.rotation, aabb.half_extents() * transform.scale.abs())
+    }
+
+    pub fn center(&self) -> Vec3 {
+        self.center
+    }
+
+    pub fn axis(&self, i: usize) -> Vec3 {
+        self.axes.col(i)
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        self.half_extents
+    }
+
+    // Smallest AABB around the box, e.g. to put it into a BVH
+    pub fn aabb(&self) -> AABB {
+        let absolute = Mat3::from_cols(self.axes.x_axis.abs(), self.axes.y_axis.abs(), self.axes.z_axis.abs());
+        let half_extents = absolute * self.half_extents;
+        AABB::new(self.center - half_extents, self.center + half_extents)
+    }
+
+    // Half of the length of the box projected onto axis
+    fn projected_radius(&self, axis: &Vec3) -> f32 {
+        (0..3).map(|i| self.half_extents[i] * self.axis(i).dot(*axis).abs()).sum()
+    }
+
+    // Separating axis test. Two convex boxes don't intersect if there is an axis
+    // on which their projections don't overlap. For boxes it is enough to test
+    // the 3 face normals of each box and the 9 cross products of their edges
+    pub fn intersects(&self, other: &OBB) -> bool {
+        let distance = other.center - self.center;
+        let mut candidate_axes = Vec::with_capacity(15);
+        for i in 0..3 {
+            candidate_axes.push(self.axis(i));
+            candidate_axes.push(other.axis(i));
+            for j in 0..3 {
+                candidate_axes.push(self.axis(i).cross(other.axis(j)));
             }
-            1 => { 
-                // Become Leaf node
-                let data_and_box = data_and_boxes.pop().unwrap();
-                return Some(BVHNode::new(data_and_box.0, data_and_box.1));
+        }
+        for axis in candidate_axes.iter() {
+            // Cross product of (nearly) parallel edges, already covered by the face normals
+            if axis.length_squared() < 1e-6 {
+                continue;
+            }
+            let separation = distance.dot(*axis).abs();
+            if separation > self.projected_radius(axis) + other.projected_radius(axis) {
+                return false;
             }
-            _ => { 
-                // Defer to children and set their combined BoundingBox as yours
-                let partitions = split_heuristic(data_and_boxes);
-                // TODO I should get rid of those copies
-                let left = BVHNode::create(partitions.0).unwrap();
-                let right = BVHNode::create(partitions.1).unwrap();
+        }
+        true
+    }
+}
 
-                return Some(BVHNode{
-                    data: None,
-                    bbox: AABB::outer(&left.bbox, &right.bbox),
-                    left: Some(Box::new(left)),
-                    right: Some(Box::new(right))
-                });
-             }
+#[test]
+fn test_obb_aabb() {
+    let obb = OBB::new(Vec3::splat(1.0), Quat::from_rotation_y(std::f32::consts::FRAC_PI_4), Vec3::splat(1.0));
+    let aabb = obb.aabb();
+    let expected = Vec3::new(2f32.sqrt(), 1.0, 2f32.sqrt());
+    assert!((aabb.max() - Vec3::splat(1.0) - expected).abs().max_element() < 1e-5);
+    // same as transforming the local box
+    let local = AABB::new(Vec3::splat(-1.0), Vec3::splat(1.0));
+    let transform = Transform::from_translation(Vec3::splat(1.0))
+        .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4));
+    let transformed = local.transformed(&transform);
+    assert!((transformed.min() - aabb.min()).abs().max_element() < 1e-5);
+    assert_eq!(OBB::from_aabb(&local, &transform).center(), Vec3::splat(1.0));
+}
+
+#[test]
+fn test_obb_intersects() {
+    let rotation = Quat::from_rotation_z(std::f32::consts::FRAC_PI_4);
+    let a = OBB::new(Vec3::ZERO, rotation, Vec3::splat(1.0));
+    // Diagonally next to each other: the enclosing AABBs overlap, the boxes don't
+    let b = OBB::new(Vec3::new(2.0, 2.0, 0.0), rotation, Vec3::splat(1.0));
+    assert!(a.aabb().intersects(&b.aabb()));
+    assert!(!a.intersects(&b));
+    assert!(!b.intersects(&a));
+    // Moved closer along the diagonal they touch
+    let c = OBB::new(Vec3::new(1.4, 1.4, 0.0), rotation, Vec3::splat(1.0));
+    assert!(a.intersects(&c));
+    // Axis aligned box against the corner of the rotated one
+    let d = OBB::new(Vec3::new(2.2, 0.0, 0.0), Quat::IDENTITY, Vec3::splat(1.0));
+    assert!(a.intersects(&d));
+    let e = OBB::new(Vec3::new(2.5, 0.0, 0.0), Quat::IDENTITY, Vec3::splat(1.0));
+    assert!(!a.intersects(&e));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitStrategy {
+    // Cut the longest axis at its geometric middle
+    Midpoint,
+    // Binned surface area heuristic with the given number of bins per axis
+    SurfaceAreaHeuristic { bins: usize },
+}
+
+impl Default for SplitStrategy {
+    fn default() -> SplitStrategy {
+        SplitStrategy::Midpoint
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct BVHBuildOptions {
+    pub split_strategy: SplitStrategy,
+    // Nodes with at most this many items are not split any further
+    pub max_leaf_size: usize,
+}
+
+impl Default for BVHBuildOptions {
+    fn default() -> BVHBuildOptions {
+        BVHBuildOptions {
+            split_strategy: SplitStrategy::default(),
+            max_leaf_size: 1,
         }
     }
+}
+
+impl BVHBuildOptions {
+    // Partitions the items in two non-empty halves. The items are moved, not copied
+    pub fn split<T>(&self, data_and_boxes: Vec<(T, AABB)>) -> (Vec<(T, AABB)>, Vec<(T, AABB)>) {
+        match self.split_strategy {
+            SplitStrategy::Midpoint => split_heuristic(data_and_boxes),
+            SplitStrategy::SurfaceAreaHeuristic { bins } => split_sah(data_and_boxes, bins),
+        }
+    }
+}
+
+// Below this many items the parallel build continues sequentially,
+// spawning tasks for small subtrees costs more than it saves
+#[cfg(feature = "parallel")]
+const PARALLEL_BUILD_THRESHOLD: usize = 1024;
+
+// A node of the linearised tree. The nodes are stored depth first, so the
+// left child always directly follows its parent and only the index of the
+// right child has to be stored. Leaves point into the items array of the BVH
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+struct BVHNode {
+    bbox: AABB,
+    // Interior nodes: index of the right child
+    // Leaves: index of the first item
+    offset: usize,
+    // Number of items of a leaf, 0 for interior nodes
+    item_count: usize,
+}
 
+impl BVHNode {
     fn is_leaf(&self) -> bool {
-        if self.left.is_none() && self.right.is_none() {
-            assert!(self.data.is_some());
-            return true;
-        } else {
-            assert!(self.data.is_none());
-            return false;
+        self.item_count > 0
+    }
+}
+
+// Building moves the items instead of cloning them, and the queries walk
+// the tree with an explicit stack instead of recursing
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "serialize", derive(serde::Serialize, serde::Deserialize))]
+pub struct BVH<T> {
+    nodes: Vec<BVHNode>,
+    items: Vec<(T, AABB)>,
+}
+
+impl<T> BVH<T> {
+    pub fn create(data_and_boxes: Vec<(T, AABB)>) -> Option<BVH<T>> {
+        BVH::create_with_options(data_and_boxes, &BVHBuildOptions::default())
+    }
+
+    pub fn create_with_options(data_and_boxes: Vec<(T, AABB)>, options: &BVHBuildOptions) -> Option<BVH<T>> {
+        if data_and_boxes.is_empty() {
+            return None;
         }
+        // A binary tree with n leaves has 2n - 1 nodes, with buckets there are fewer leaves
+        let mut bvh = BVH {
+            nodes: Vec::with_capacity(2 * data_and_boxes.len() - 1),
+            items: Vec::with_capacity(data_and_boxes.len()),
+        };
+        bvh.build(data_and_boxes, options);
+        Some(bvh)
     }
 
-    pub fn get_closest(&self, position: &Vec3) -> Option<(T, AABB)> {
-        // closest geometric distance to bounding box surface
-        // If position is within 2 bounding boxes, the element
-        // "further in" will be returned
-        if self.is_leaf() {
-            return Some((self.data.as_ref().unwrap().clone(), self.bbox.clone()));
+    // Appends the subtree for data_and_boxes and returns the index of its root
+    fn build(&mut self, data_and_boxes: Vec<(T, AABB)>, options: &BVHBuildOptions) -> usize {
+        let index = self.nodes.len();
+        if data_and_boxes.len() <= options.max_leaf_size.max(1) {
+            let bbox = data_and_boxes.iter().fold(data_and_boxes[0].1, |outer, current| AABB::outer(&outer, &current.1));
+            self.nodes.push(BVHNode { bbox, offset: self.items.len(), item_count: data_and_boxes.len() });
+            self.items.extend(data_and_boxes);
+            return index;
         }
 
-        let left_contains;
-        if let Some(left) = &self.left {
-            left_contains = left.bbox.contains(&position);
-        } else {
-            // Only right is some
-            return self.right.as_ref().unwrap().get_closest(position);
+        // Reserve the slot, the box and right child are only known after the children are built
+        self.nodes.push(BVHNode { bbox: AABB::default(), offset: 0, item_count: 0 });
+        let (before_split, after_split) = options.split(data_and_boxes);
+        let left = self.build(before_split, options);
+        let right = self.build(after_split, options);
+        self.nodes[index].bbox = AABB::outer(&self.nodes[left].bbox, &self.nodes[right].bbox);
+        self.nodes[index].offset = right;
+        index
+    }
+
+    // Puts left and right under a new root. The nodes of right move behind
+    // those of left, exactly where a sequential build would have put them
+    #[cfg(feature = "parallel")]
+    fn new_interior(left: BVH<T>, right: BVH<T>) -> BVH<T> {
+        let right_root = 1 + left.nodes.len();
+        let mut nodes = Vec::with_capacity(1 + left.nodes.len() + right.nodes.len());
+        nodes.push(BVHNode {
+            bbox: AABB::outer(&left.nodes[0].bbox, &right.nodes[0].bbox),
+            offset: right_root,
+            item_count: 0,
+        });
+        let shift = |node: &BVHNode, node_shift: usize, item_shift: usize| {
+            let offset = if node.is_leaf() { node.offset + item_shift } else { node.offset + node_shift };
+            BVHNode { offset, ..*node }
+        };
+        nodes.extend(left.nodes.iter().map(|node| shift(node, 1, 0)));
+        nodes.extend(right.nodes.iter().map(|node| shift(node, right_root, left.items.len())));
+        let mut items = left.items;
+        items.extend(right.items);
+        BVH { nodes, items }
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty()
+    }
+
+    pub fn depth(&self) -> usize {
+        let mut depth = 0;
+        let mut stack = vec![(0, 1)];
+        while let Some((index, node_depth)) = stack.pop() {
+            depth = depth.max(node_depth);
+            if !self.nodes[index].is_leaf() {
+                let (left, right) = self.children(index);
+                stack.push((left, node_depth + 1));
+                stack.push((right, node_depth + 1));
+            }
         }
-        let right_contains;
-        if let Some(right) = &self.right {
-            right_contains = right.bbox.contains(&position);
-        } else {
-            // Only left is some
-            return self.left.as_ref().unwrap().get_closest(position);
-        }
-        // Both right and left are some
-        let left_closest;
-        let right_closest;
-        if left_contains && !right_contains{
-            // I can potentially prune the right branch
-            left_closest = self.left.as_ref().unwrap().get_closest(position).unwrap();
-            let dist = left_closest.1.distance(position);
-            if dist < self.right.as_ref().unwrap().bbox.distance(position) {
-                return Some(left_closest);
-            } else {
-                right_closest = self.left.as_ref().unwrap().get_closest(position).unwrap();
-            }
-        } 
-        else if right_contains && !left_contains {
-            // I can potentially prune the left branch
-            right_closest = self.left.as_ref().unwrap().get_closest(position).unwrap();
-            let dist = right_closest.1.distance(position);
-            if dist < self.left.as_ref().unwrap().bbox.distance(position) {
-                return Some(right_closest);
+        depth
+    }
+
+    fn children(&self, index: usize) -> (usize, usize) {
+        (index + 1, self.nodes[index].offset)
+    }
+
+    fn leaf_items(&self, node: &BVHNode) -> &[(T, AABB)] {
+        &self.items[node.offset..node.offset + node.item_count]
+    }
+
+    // Best-first traversal: always expand the node whose bounding box is
+    // closest to position. A child is never closer than its parent, so items
+    // come out of the queue sorted by distance and everything left in the
+    // queue once n items are found can be pruned. Returns item indices
+    fn closest_items(&self, position: &Vec3, n: usize) -> Vec<(usize, f32)> {
+        let mut closest = Vec::with_capacity(n);
+        if n == 0 {
+            return closest;
+        }
+        let mut queue = BinaryHeap::new();
+        queue.push(QueueEntry { distance: self.nodes[0].bbox.distance(position), node: Visit::Node(0) });
+        while let Some(QueueEntry { distance, node }) = queue.pop() {
+            match node {
+                Visit::Item(item) => {
+                    closest.push((item, distance));
+                    if closest.len() == n {
+                        break;
+                    }
+                }
+                Visit::Node(index) if self.nodes[index].is_leaf() => {
+                    let offset = self.nodes[index].offset;
+                    for (i, (_, bbox)) in self.leaf_items(&self.nodes[index]).iter().enumerate() {
+                        queue.push(QueueEntry { distance: bbox.distance(position), node: Visit::Item(offset + i) });
+                    }
+                }
+                Visit::Node(index) => {
+                    let (left, right) = self.children(index);
+                    queue.push(QueueEntry { distance: self.nodes[left].bbox.distance(position), node: Visit::Node(left) });
+                    queue.push(QueueEntry { distance: self.nodes[right].bbox.distance(position), node: Visit::Node(right) });
+                }
+            }
+        }
+        closest
+    }
+
+    // Visits every item whose box passes overlaps, skipping subtrees whose box doesn't
+    fn for_each_overlapping<F, V>(&self, overlaps: F, mut visit: V)
+    where F: Fn(&AABB) -> bool, V: FnMut(&T, &AABB) {
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !overlaps(&node.bbox) {
+                continue;
+            }
+            if node.is_leaf() {
+                for (data, bbox) in self.leaf_items(node) {
+                    if overlaps(bbox) {
+                        visit(data, bbox);
+                    }
+                }
             } else {
-                left_closest = self.left.as_ref().unwrap().get_closest(position).unwrap();
+                let (left, right) = self.children(index);
+                stack.push(right);
+                stack.push(left);
             }
-        } 
-        else {
-            // TODO I can potentially prune if I get lucky and the same condition
-            // As in the cases above is true
-            left_closest = self.left.as_ref().unwrap().get_closest(position).unwrap();
-            right_closest = self.right.as_ref().unwrap().get_closest(position).unwrap();
-        } 
-        // Pick the closer one
-        let left_dist = left_closest.1.distance(position);
-        let right_dist = right_closest.1.distance(position);
-        if left_dist < right_dist { 
-            return Some(left_closest);
         }
-        else {
-            return Some(right_closest);
+    }
+}
+
+impl<T> BVH<T>
+where T: Clone {
+    // closest geometric distance to bounding box surface
+    // If position is within 2 bounding boxes, the element
+    // "further in" will be returned, on ties the later item wins
+    pub fn get_closest(&self, position: &Vec3) -> Option<(T, AABB)> {
+        // Depth first, left before right, skipping subtrees that are
+        // farther away than the closest item found so far
+        let mut closest: Option<(usize, f32)> = None;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if closest.map_or(false, |closest| node.bbox.distance(position) > closest.1) {
+                continue;
+            }
+            if node.is_leaf() {
+                for (i, (_, bbox)) in self.leaf_items(node).iter().enumerate() {
+                    let distance = bbox.distance(position);
+                    if closest.map_or(true, |closest| distance <= closest.1) {
+                        closest = Some((node.offset + i, distance));
+                    }
+                }
+            } else {
+                let (left, right) = self.children(index);
+                stack.push(right);
+                stack.push(left);
+            }
         }
+        closest.map(|(item, _)| self.items[item].clone())
     }
 
-    pub fn get_in_radius (&self, position: &Vec3, radius: f32) -> Option<Vec<T>> {
-        if self.bbox.distance(position) > radius {
+    // The n closest items with their distances, sorted by distance
+    pub fn get_n_closest(&self, position: &Vec3, n: usize) -> Vec<(T, f32)> {
+        self.closest_items(position, n)
+            .into_iter()
+            .map(|(item, distance)| (self.items[item].0.clone(), distance))
+            .collect()
+    }
+
+    pub fn get_in_radius(&self, position: &Vec3, radius: f32) -> Option<Vec<T>> {
+        if self.nodes[0].bbox.distance(position) > radius {
             return None;
-        } 
-        if self.is_leaf() {
-            return Some(vec![self.data.as_ref().unwrap().clone()]);
         }
-        let mut return_data = Vec::new();
-        if let Some(left) = &self.left {
-            if let Some(mut data) = left.get_in_radius(position, radius) {
-                return_data.append(&mut data);
+        let mut in_radius = Vec::new();
+        self.for_each_overlapping(|bbox| bbox.distance(position) <= radius, |data, _| in_radius.push(data.clone()));
+        Some(in_radius)
+    }
+
+    // Returns all items whose bounding box overlaps bbox
+    pub fn query_aabb(&self, bbox: &AABB) -> Vec<T> {
+        let mut overlapping = Vec::new();
+        self.for_each_overlapping(|other| other.intersects(bbox), |data, _| overlapping.push(data.clone()));
+        overlapping
+    }
+
+    // Returns all items whose bounding box overlaps the sphere
+    pub fn query_sphere(&self, center: &Vec3, radius: f32) -> Vec<T> {
+        let mut overlapping = Vec::new();
+        self.for_each_overlapping(|other| other.intersects_sphere(center, radius), |data, _| overlapping.push(data.clone()));
+        overlapping
+    }
+
+    // Returns all items whose bounding box is at least partially inside the
+    // frustum, see AABB::intersects_frustum for the plane convention
+    pub fn query_frustum(&self, planes: &[Vec4]) -> Vec<T> {
+        let mut overlapping = Vec::new();
+        self.for_each_overlapping(|other| other.intersects_frustum(planes), |data, _| overlapping.push(data.clone()));
+        overlapping
+    }
+
+    // Returns every pair of items whose bounding boxes overlap, each pair once
+    pub fn overlapping_pairs(&self) -> Vec<(T, T)> {
+        let mut pairs = Vec::new();
+        // Pairs within each subtree, then pairs between the two children.
+        // The subtrees are disjoint, so no pair is found twice
+        let mut within = vec![0];
+        let mut between = Vec::new();
+        while let Some(index) = within.pop() {
+            let node = &self.nodes[index];
+            if !node.is_leaf() {
+                let (left, right) = self.children(index);
+                within.push(left);
+                within.push(right);
+                between.push((left, right));
+                continue;
+            }
+            let items = self.leaf_items(node);
+            for (i, (data, bbox)) in items.iter().enumerate() {
+                for (other_data, other_bbox) in items[i + 1..].iter() {
+                    if bbox.intersects(other_bbox) {
+                        pairs.push((data.clone(), other_data.clone()));
+                    }
+                }
             }
         }
-        if let Some(right) = &self.right {
-            if let Some(mut data) = right.get_in_radius(position, radius) {
-                return_data.append(&mut data);
+        while let Some((a, b)) = between.pop() {
+            let (node_a, node_b) = (&self.nodes[a], &self.nodes[b]);
+            if !node_a.bbox.intersects(&node_b.bbox) {
+                continue;
+            }
+            match (node_a.is_leaf(), node_b.is_leaf()) {
+                (true, true) => {
+                    for (data, bbox) in self.leaf_items(node_a) {
+                        for (other_data, other_bbox) in self.leaf_items(node_b) {
+                            if bbox.intersects(other_bbox) {
+                                pairs.push((data.clone(), other_data.clone()));
+                            }
+                        }
+                    }
+                }
+                // Descend into the larger node first, that prunes more
+                (true, false) => {
+                    let (left, right) = self.children(b);
+                    between.push((a, left));
+                    between.push((a, right));
+                }
+                (false, false) if node_b.bbox.surface_area() > node_a.bbox.surface_area() => {
+                    let (left, right) = self.children(b);
+                    between.push((a, left));
+                    between.push((a, right));
+                }
+                (false, _) => {
+                    let (left, right) = self.children(a);
+                    between.push((left, b));
+                    between.push((right, b));
+                }
             }
         }
-        Some(return_data)
+        pairs
+    }
+
+    // Returns every pair of an item of this tree and an item of other
+    // whose bounding boxes overlap
+    pub fn overlapping_pairs_with<U: Clone>(&self, other: &BVH<U>) -> Vec<(T, U)> {
+        let mut pairs = Vec::new();
+        let mut stack = vec![(0, 0)];
+        while let Some((a, b)) = stack.pop() {
+            let (node_a, node_b) = (&self.nodes[a], &other.nodes[b]);
+            if !node_a.bbox.intersects(&node_b.bbox) {
+                continue;
+            }
+            match (node_a.is_leaf(), node_b.is_leaf()) {
+                (true, true) => {
+                    for (data, bbox) in self.leaf_items(node_a) {
+                        for (other_data, other_bbox) in other.leaf_items(node_b) {
+                            if bbox.intersects(other_bbox) {
+                                pairs.push((data.clone(), other_data.clone()));
+                            }
+                        }
+                    }
+                }
+                // Descend into the larger node first, that prunes more
+                (true, false) => {
+                    let (left, right) = other.children(b);
+                    stack.push((a, left));
+                    stack.push((a, right));
+                }
+                (false, false) if node_b.bbox.surface_area() > node_a.bbox.surface_area() => {
+                    let (left, right) = other.children(b);
+                    stack.push((a, left));
+                    stack.push((a, right));
+                }
+                (false, _) => {
+                    let (left, right) = self.children(a);
+                    stack.push((left, b));
+                    stack.push((right, b));
+                }
+            }
+        }
+        pairs
+    }
+
+    // Returns the first item whose bounding box is hit by the ray and the
+    // ray parameter t of the hit. t is in multiples of direction
+    pub fn raycast(&self, origin: &Vec3, direction: &Vec3) -> Option<(T, f32)> {
+        let mut closest_hit: Option<(usize, f32)> = None;
+        let mut stack = Vec::new();
+        if let Some(t) = self.nodes[0].bbox.ray_intersect(origin, direction) {
+            stack.push((0, t));
+        }
+        while let Some((index, t)) = stack.pop() {
+            if closest_hit.map_or(false, |hit| t >= hit.1) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            if node.is_leaf() {
+                for (i, (_, bbox)) in self.leaf_items(node).iter().enumerate() {
+                    if let Some(t) = bbox.ray_intersect(origin, direction) {
+                        if closest_hit.map_or(true, |hit| t < hit.1) {
+                            closest_hit = Some((node.offset + i, t));
+                        }
+                    }
+                }
+                continue;
+            }
+            // Push the farther child first, so the nearer one is visited
+            // first and its hit can prune the other one
+            let (left, right) = self.children(index);
+            let left_t = self.nodes[left].bbox.ray_intersect(origin, direction);
+            let right_t = self.nodes[right].bbox.ray_intersect(origin, direction);
+            match (left_t, right_t) {
+                (Some(left_t), Some(right_t)) if left_t <= right_t => {
+                    stack.push((right, right_t));
+                    stack.push((left, left_t));
+                }
+                (Some(left_t), Some(right_t)) => {
+                    stack.push((left, left_t));
+                    stack.push((right, right_t));
+                }
+                (Some(left_t), None) => stack.push((left, left_t)),
+                (None, Some(right_t)) => stack.push((right, right_t)),
+                (None, None) => {}
+            }
+        }
+        closest_hit.map(|(item, t)| (self.items[item].0.clone(), t))
+    }
+
+    // Returns all items whose bounding box is hit by the ray, sorted by t
+    pub fn raycast_all(&self, origin: &Vec3, direction: &Vec3) -> Vec<(T, f32)> {
+        let mut hits = Vec::new();
+        self.for_each_overlapping(
+            |bbox| bbox.ray_intersect(origin, direction).is_some(),
+            |data, bbox| hits.push((data.clone(), bbox.ray_intersect(origin, direction).unwrap())),
+        );
+        hits.sort_by(|a, b| a.1.total_cmp(&b.1));
+        hits
+    }
+}
+
+// Indices into AABB::corners of the 12 edges of a box
+const AABB_EDGES: [(usize, usize); 12] = [
+    (0, 1), (2, 3), (4, 5), (6, 7),
+    (0, 2), (1, 3), (4, 6), (5, 7),
+    (0, 4), (1, 5), (2, 6), (3, 7),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BVHStats {
+    pub node_count: usize,
+    pub leaf_count: usize,
+    pub item_count: usize,
+    pub max_depth: usize,
+    // Averaged over the leaves, the root is at depth 1
+    pub average_leaf_depth: f32,
+    // Expected cost of a query relative to testing a single item, with
+    // traversal and item tests weighted equally. Every node is entered with
+    // the probability surface_area(node) / surface_area(root)
+    pub sah_cost: f32,
+    // Summed volume in which the boxes of two siblings overlap.
+    // Queries in there have to descend into both children
+    pub overlap_volume: f32,
+}
+
+impl<T> BVH<T> {
+    pub fn stats(&self) -> BVHStats {
+        let mut stats = BVHStats::default();
+        let mut leaf_depth_sum = 0;
+        // A degenerate root (all items in one point) would divide by zero
+        let root_area = self.nodes[0].bbox.surface_area().max(f32::EPSILON);
+        let mut stack = vec![(0, 1)];
+        while let Some((index, depth)) = stack.pop() {
+            let node = &self.nodes[index];
+            let hit_probability = node.bbox.surface_area() / root_area;
+            stats.node_count += 1;
+            stats.max_depth = stats.max_depth.max(depth);
+            if node.is_leaf() {
+                stats.leaf_count += 1;
+                stats.item_count += node.item_count;
+                stats.sah_cost += hit_probability * node.item_count as f32;
+                leaf_depth_sum += depth;
+                continue;
+            }
+            let (left, right) = self.children(index);
+            stats.sah_cost += hit_probability;
+            if let Some(overlap) = self.nodes[left].bbox.intersection(&self.nodes[right].bbox) {
+                stats.overlap_volume += overlap.volume();
+            }
+            stack.push((left, depth + 1));
+            stack.push((right, depth + 1));
+        }
+        stats.average_leaf_depth = leaf_depth_sum as f32 / stats.leaf_count as f32;
+        stats
+    }
+
+    // Checks the structural invariants of the tree: the nodes form a depth
+    // first binary tree, every item belongs to exactly one leaf and every box
+    // encloses the boxes below it. Unlike the queries this does not panic,
+    // even if the indices are out of range
+    pub fn validate(&self) -> Result<(), String> {
+        if self.nodes.is_empty() {
+            return Err("The tree has no nodes".to_string());
+        }
+        let mut visited = vec![false; self.nodes.len()];
+        let mut referenced = vec![false; self.items.len()];
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            if visited[index] {
+                return Err(format!("Node {} is reachable twice", index));
+            }
+            visited[index] = true;
+            let node = &self.nodes[index];
+            if node.is_leaf() {
+                // A corrupt snapshot can hold offsets that overflow
+                let end = match node.offset.checked_add(node.item_count) {
+                    Some(end) if end <= self.items.len() => end,
+                    _ => return Err(format!("Leaf {} points past the items", index)),
+                };
+                let leaf_range = node.offset..end;
+                for (item, (_, bbox)) in leaf_range.clone().zip(self.items[leaf_range].iter()) {
+                    if referenced[item] {
+                        return Err(format!("Item {} belongs to several leaves", item));
+                    }
+                    referenced[item] = true;
+                    if !node.bbox.contains_aabb(bbox) {
+                        return Err(format!("Leaf {} does not enclose its item {:?}", index, bbox));
+                    }
+                }
+                continue;
+            }
+            let (left, right) = self.children(index);
+            // Depth first order, the right child follows the left subtree
+            if right <= left || right >= self.nodes.len() {
+                return Err(format!("Node {} has an invalid right child {}", index, right));
+            }
+            for child in [left, right] {
+                if !node.bbox.contains_aabb(&self.nodes[child].bbox) {
+                    return Err(format!("Node {} does not enclose its child {:?}", index, self.nodes[child].bbox));
+                }
+                stack.push(child);
+            }
+        }
+        if let Some(index) = visited.iter().position(|visited| !visited) {
+            return Err(format!("Node {} is not part of the tree", index));
+        }
+        if let Some(item) = referenced.iter().position(|referenced| !referenced) {
+            return Err(format!("Item {} belongs to no leaf", item));
+        }
+        Ok(())
+    }
+
+    // The boxes of all nodes, grouped by depth. Index 0 holds the root
+    pub fn boxes_by_depth(&self) -> Vec<Vec<AABB>> {
+        let mut levels = Vec::new();
+        let mut current_level = vec![0];
+        while !current_level.is_empty() {
+            levels.push(current_level.iter().map(|index| self.nodes[*index].bbox).collect());
+            current_level = current_level
+                .iter()
+                .filter(|index| !self.nodes[**index].is_leaf())
+                .flat_map(|index| {
+                    let (left, right) = self.children(*index);
+                    [left, right]
+                })
+                .collect();
+        }
+        levels
+    }
+
+    // Writes the boxes as wireframes in the Wavefront OBJ format, with one
+    // group per depth level (depth_0 is the root), so they can be toggled in
+    // a model viewer
+    pub fn write_obj<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writeln!(writer, "# BVH with {} nodes", self.nodes.len())?;
+        // OBJ indices are 1-based and global over the whole file
+        let mut first_vertex = 1;
+        for (depth, boxes) in self.boxes_by_depth().iter().enumerate() {
+            writeln!(writer, "g depth_{}", depth)?;
+            for bbox in boxes {
+                for corner in bbox.corners().iter() {
+                    writeln!(writer, "v {} {} {}", corner.x, corner.y, corner.z)?;
+                }
+                for (a, b) in AABB_EDGES.iter() {
+                    writeln!(writer, "l {} {}", first_vertex + a, first_vertex + b)?;
+                }
+                first_vertex += 8;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Either a node or a single item of a leaf, waiting in the queue of get_n_closest
+#[derive(Clone, Copy)]
+enum Visit {
+    Node(usize),
+    Item(usize),
+}
+
+#[cfg(feature = "parallel")]
+impl<T> BVH<T>
+where T: Send {
+    // Builds the two subtrees of large nodes on different threads. Splitting
+    // is deterministic, so the result is identical to create_with_options
+    pub fn create_parallel(data_and_boxes: Vec<(T, AABB)>, options: &BVHBuildOptions) -> Option<BVH<T>> {
+        if data_and_boxes.len() < PARALLEL_BUILD_THRESHOLD || data_and_boxes.len() <= options.max_leaf_size {
+            return BVH::create_with_options(data_and_boxes, options);
+        }
+        let (before_split, after_split) = options.split(data_and_boxes);
+        let (left, right) = rayon::join(
+            || BVH::create_parallel(before_split, options).unwrap(),
+            || BVH::create_parallel(after_split, options).unwrap(),
+        );
+        Some(BVH::new_interior(left, right))
+    }
+}
+
+// Snapshots are rejected beyond this size, so a corrupt length
+// prefix can't make read_binary allocate whatever it claims
+#[cfg(feature = "serialize")]
+pub const MAX_SNAPSHOT_BYTES: u64 = 1 << 30;
+
+#[cfg(feature = "serialize")]
+impl<T> BVH<T> {
+    // Compact binary snapshot, so trees of static geometry can be
+    // built once and loaded at startup
+    pub fn write_binary<W: Write>(&self, writer: W) -> bincode::Result<()>
+    where T: serde::Serialize {
+        bincode::serialize_into(writer, self)
+    }
+
+    // Rejects snapshots that decode but don't form a valid tree,
+    // the queries rely on the invariants checked by validate
+    pub fn read_binary<R: io::Read>(reader: R) -> bincode::Result<BVH<T>>
+    where T: serde::de::DeserializeOwned {
+        use bincode::Options;
+        // The same encoding as bincode::serialize_into in write_binary
+        let bvh: BVH<T> = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(MAX_SNAPSHOT_BYTES)
+            .deserialize_from(reader)?;
+        bvh.validate().map_err(|message| Box::new(bincode::ErrorKind::Custom(message)))?;
+        Ok(bvh)
+    }
+}
+
+// Entry of the priority queue used by the closest-first queries.
+// BinaryHeap is a max-heap, so the ordering is reversed to pop the
+// closest node first
+pub struct QueueEntry<N> {
+    pub distance: f32,
+    pub node: N,
+}
+
+impl<N> PartialEq for QueueEntry<N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance.total_cmp(&other.distance) == Ordering::Equal
+    }
+}
+
+impl<N> Eq for QueueEntry<N> {}
+
+impl<N> PartialOrd for QueueEntry<N> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<N> Ord for QueueEntry<N> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
     }
-    
-    // pub fn get_n_closest(&self, position: &Vec3, n: i32) -> Option<Vec<T>> {
-    //     None
-    // }
 }
 
 fn test_construct_linear_boxes(n: i32) -> Vec<(i32, AABB)> {
@@ -227,14 +1063,32 @@ fn test_construct_linear_boxes(n: i32) -> Vec<(i32, AABB)> {
 #[test]
 fn test_bvh_create() {
     let data_and_boxes = test_construct_linear_boxes(5);
-    let root = BVHNode::create(data_and_boxes);
+    let root = BVH::create(data_and_boxes);
     assert!(root.is_some());
 }
 
+#[test]
+fn test_bvh_layout() {
+    // Not Clone, to make sure the build never copies the data
+    #[derive(Debug, PartialEq)]
+    struct Item(i32);
+    let data_and_boxes: Vec<(Item, AABB)> = test_construct_linear_boxes(16)
+        .into_iter()
+        .map(|(i, bbox)| (Item(i), bbox))
+        .collect();
+    let bvh = BVH::create(data_and_boxes).unwrap();
+    assert_eq!(bvh.len(), 16);
+    assert_eq!(bvh.nodes.len(), 2 * 16 - 1);
+    // The left child directly follows its parent
+    assert!(bvh.nodes.iter().enumerate().all(|(i, node)| node.is_leaf() || node.offset > i + 1));
+    assert_eq!(bvh.validate(), Ok(()));
+    assert!(BVH::<Item>::create(Vec::new()).is_none());
+}
+
 #[test]
 fn test_get_closest() {
     let data_and_boxes = test_construct_linear_boxes(5);
-    let root_opt = BVHNode::create(data_and_boxes);
+    let root_opt = BVH::create(data_and_boxes);
     assert!(root_opt.is_some());
     let root = root_opt.unwrap();
     // on the border
@@ -251,64 +1105,273 @@ fn test_get_closest() {
     assert_eq!(closest_4.unwrap().0, 4);
 }
 
-// Returns the first index thats part of the second section
-fn split_heuristic<T: Clone>(mut data_and_boxes: Vec<(T, AABB)>) 
-    -> (Vec<(T, AABB)>, Vec<(T, AABB)>) 
+#[test]
+fn test_get_closest_in_right_subtree() {
+    let data_and_boxes = test_construct_linear_boxes(8);
+    let options = BVHBuildOptions { max_leaf_size: 2, ..Default::default() };
+    let root = BVH::create_with_options(data_and_boxes.clone(), &options).unwrap();
+    // Inside the right child only, and between two of its items
+    for position in [Vec3::splat(12.5), Vec3::splat(13.4), Vec3::splat(14.5), Vec3::splat(30.0)] {
+        let expected = data_and_boxes.iter()
+            .min_by(|a, b| a.1.distance(&position).total_cmp(&b.1.distance(&position)))
+            .unwrap();
+        assert_eq!(root.get_closest(&position).unwrap().0, expected.0);
+    }
+}
+
+#[test]
+fn test_raycast() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    // along the diagonal, front to back
+    let hit = root.raycast(&Vec3::splat(-1.0), &Vec3::splat(1.0)).unwrap();
+    assert_eq!(hit, (0, 1.0));
+    // back to front
+    let hit = root.raycast(&Vec3::splat(20.0), &Vec3::splat(-1.0)).unwrap();
+    assert_eq!(hit, (4, 11.0));
+    // starting inside a box
+    let hit = root.raycast(&Vec3::splat(4.5), &Vec3::splat(1.0)).unwrap();
+    assert_eq!(hit, (2, 0.0));
+    // parallel to the boxes, but next to them
+    assert!(root.raycast(&Vec3::new(-1.0, 0.0, 5.0), &Vec3::X).is_none());
+    // pointing away
+    assert!(root.raycast(&Vec3::splat(-1.0), &Vec3::splat(-1.0)).is_none());
+}
+
+#[test]
+fn test_raycast_all() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    let hits = root.raycast_all(&Vec3::splat(3.5), &Vec3::splat(1.0));
+    let data: Vec<i32> = hits.iter().map(|h| h.0).collect();
+    assert_eq!(data, vec![2, 3, 4]);
+    assert!(hits.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert!(root.raycast_all(&Vec3::splat(-1.0), &Vec3::splat(-1.0)).is_empty());
+}
+
+#[test]
+fn test_query_aabb() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    let mut overlapping = root.query_aabb(&AABB::new(Vec3::splat(2.5), Vec3::splat(4.5)));
+    overlapping.sort();
+    assert_eq!(overlapping, vec![1, 2]);
+    // touching counts as overlapping
+    assert_eq!(root.query_aabb(&AABB::new(Vec3::splat(9.0), Vec3::splat(12.0))), vec![4]);
+    assert!(root.query_aabb(&AABB::new(Vec3::splat(1.2), Vec3::splat(1.8))).is_empty());
+}
+
+#[test]
+fn test_query_sphere() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    let mut overlapping = root.query_sphere(&Vec3::splat(3.5), 1.0);
+    overlapping.sort();
+    assert_eq!(overlapping, vec![1, 2]);
+    assert!(root.query_sphere(&Vec3::splat(1.5), 0.8).is_empty());
+}
+
+#[test]
+fn test_query_frustum() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    // Unit cube like frustum between x = 3.5 and x = 6.5, unbounded otherwise
+    let planes = [
+        Vec4::new(1.0, 0.0, 0.0, -3.5),
+        Vec4::new(-1.0, 0.0, 0.0, 6.5),
+    ];
+    let mut inside = root.query_frustum(&planes);
+    inside.sort();
+    assert_eq!(inside, vec![2, 3]);
+    // Slanted plane, only keeps points with x + y + z >= 20
+    let planes = [Vec4::new(1.0, 1.0, 1.0, -20.0)];
+    assert_eq!(root.query_frustum(&planes), vec![3, 4]);
+}
+
+// Overlapping boxes, moved by offset on every axis
+#[cfg(test)]
+fn test_construct_overlapping_boxes(n: usize, offset: f32) -> Vec<(usize, AABB)> {
+    scattered_boxes(n, [16, 7, 3], 0.5, 0.8)
+        .into_iter()
+        .map(|(i, bbox)| (i, bbox.translated(&Vec3::splat(offset))))
+        .collect()
+}
+
+#[test]
+fn test_overlapping_pairs() {
+    let data_and_boxes = test_construct_overlapping_boxes(64, 0.0);
+    let expected = brute_force_pairs(&data_and_boxes, &data_and_boxes, true);
+    assert!(!expected.is_empty());
+    for max_leaf_size in [1, 4] {
+        let options = BVHBuildOptions { max_leaf_size, ..Default::default() };
+        let root = BVH::create_with_options(data_and_boxes.clone(), &options).unwrap();
+        let mut pairs: Vec<(usize, usize)> = root.overlapping_pairs()
+            .into_iter()
+            .map(|(a, b)| (a.min(b), a.max(b)))
+            .collect();
+        pairs.sort();
+        // no duplicates
+        let length = pairs.len();
+        pairs.dedup();
+        assert_eq!(pairs.len(), length);
+        let mut expected = expected.clone();
+        expected.sort();
+        assert_eq!(pairs, expected);
+    }
+}
+
+#[test]
+fn test_overlapping_pairs_with() {
+    let static_boxes = test_construct_overlapping_boxes(40, 0.0);
+    let dynamic_boxes = test_construct_overlapping_boxes(24, 1.3);
+    let static_root = BVH::create(static_boxes.clone()).unwrap();
+    let dynamic_root = BVH::create(dynamic_boxes.clone()).unwrap();
+    let mut expected = brute_force_pairs(&static_boxes, &dynamic_boxes, false);
+    assert!(!expected.is_empty());
+    expected.sort();
+    let mut pairs = static_root.overlapping_pairs_with(&dynamic_root);
+    pairs.sort();
+    assert_eq!(pairs, expected);
+
+    let single = BVH::create(vec![(0, AABB::new(Vec3::splat(-2.0), Vec3::splat(-1.0)))]).unwrap();
+    assert!(single.overlapping_pairs().is_empty());
+    assert!(single.overlapping_pairs_with(&static_root).is_empty());
+}
+
+#[test]
+fn test_get_n_closest() {
+    let data_and_boxes = test_construct_linear_boxes(5);
+    let root = BVH::create(data_and_boxes).unwrap();
+    // sorted by distance
+    let closest = root.get_n_closest(&Vec3::splat(20.0), 3);
+    let data: Vec<i32> = closest.iter().map(|c| c.0).collect();
+    assert_eq!(data, vec![4, 3, 2]);
+    assert!(closest.windows(2).all(|w| w[0].1 <= w[1].1));
+    // within a box
+    let closest = root.get_n_closest(&Vec3::splat(2.5), 2);
+    assert_eq!(closest[0].0, 1);
+    assert_eq!(closest[0].1, -0.5);
+    // more than available
+    assert_eq!(root.get_n_closest(&Vec3::splat(0.0), 10).len(), 5);
+    assert!(root.get_n_closest(&Vec3::splat(0.0), 0).is_empty());
+}
+
+#[test]
+fn test_get_n_closest_matches_brute_force() {
+    let data_and_boxes = scattered_boxes(64, [64, 17, 13], 1.0, 0.5);
+    let root = BVH::create(data_and_boxes.clone()).unwrap();
+    let position = Vec3::new(30.0, 4.0, 7.0);
+    let closest: Vec<f32> = root.get_n_closest(&position, 8).iter().map(|c| c.1).collect();
+    assert_eq!(closest, brute_force_n_closest(&data_and_boxes, &position, 8));
+}
+
+// Splits in the geometric middle of the axis with the largest spread
+fn split_heuristic<T>(mut data_and_boxes: Vec<(T, AABB)>)
+    -> (Vec<(T, AABB)>, Vec<(T, AABB)>)
 {
     assert!(data_and_boxes.len() > 1);
 
     let outer_box = data_and_boxes.iter().fold(
-        data_and_boxes.get(0).unwrap().1, 
+        data_and_boxes[0].1,
         |outer, current| {
         AABB::outer(&outer, &current.1)
     });
-    // Find partition Axis (x, y or z)
-    // Choose the one, where the outer centers have the largest distance 
     let outer_box_dimensions: Vec3 = outer_box.max - outer_box.min;
-
-    // Split in the geometric middle of the axis with largest spread
-    let mut before_split = Vec::new();
-    let mut after_split = Vec::new();
     let max_dimension = outer_box_dimensions.max_element();
-    if outer_box_dimensions.x == max_dimension { 
-        let center = outer_box.min.x + (outer_box_dimensions/2.0).x;
-        data_and_boxes.sort_by(|a, b| { a.1.center.x.partial_cmp(&b.1.center.x).unwrap() });
-        for p in data_and_boxes.iter() {
-            if p.1.center.x <= center {
-                // TODO I should prevent the copies of data Members by 
-                before_split.push(p.clone());
-            } else {
-                after_split.push(p.clone());
-            }
-        }
+    let axis = if outer_box_dimensions.x == max_dimension { 0 }
+        else if outer_box_dimensions.y == max_dimension { 1 }
+        else { 2 };
+    let center = outer_box.min[axis] + outer_box_dimensions[axis] / 2.0;
+
+    // Sorting lets me split the Vec in place instead of copying into two new ones
+    data_and_boxes.sort_by(|a, b| { a.1.center[axis].partial_cmp(&b.1.center[axis]).unwrap() });
+    let mut split_index = data_and_boxes.partition_point(|p| p.1.center[axis] <= center);
+    // All centers ended up on one side, e.g. because they coincide.
+    // Split in half instead
+    if split_index == 0 || split_index == data_and_boxes.len() {
+        split_index = data_and_boxes.len() / 2;
     }
-    else if outer_box_dimensions.y == max_dimension { 
-        let center = outer_box.min.y + (outer_box_dimensions/2.0).y;
-        data_and_boxes.sort_by(|a, b| { a.1.center.y.partial_cmp(&b.1.center.y).unwrap() });
-        for p in data_and_boxes.iter() {
-            if p.1.center.y <= center {
-                // TODO I should prevent the copies of data Members by 
-                before_split.push(p.clone());
-            } else {
-                after_split.push(p.clone());
+    let after_split = data_and_boxes.split_off(split_index);
+    (data_and_boxes, after_split)
+}
+
+// Binned surface area heuristic: sort the box centers into bins along each
+// axis and pick the bin boundary with the lowest
+// area(left) * count(left) + area(right) * count(right)
+fn split_sah<T>(mut data_and_boxes: Vec<(T, AABB)>, bins: usize)
+    -> (Vec<(T, AABB)>, Vec<(T, AABB)>)
+{
+    assert!(data_and_boxes.len() > 1);
+    assert!(bins > 1);
+
+    let first_center = data_and_boxes[0].1.center;
+    let (center_min, center_max) = data_and_boxes.iter().fold(
+        (first_center, first_center),
+        |(min, max), current| (min.min(current.1.center), max.max(current.1.center)));
+    let extent = center_max - center_min;
+    let bin_index = |center: &Vec3, axis: usize| -> usize {
+        let relative = (center[axis] - center_min[axis]) / extent[axis];
+        ((relative * bins as f32) as usize).min(bins - 1)
+    };
+
+    // (cost, axis, first bin of the second partition)
+    let mut best_split: Option<(f32, usize, usize)> = None;
+    for axis in 0..3 {
+        if extent[axis] <= 0.0 {
+            continue;
+        }
+        let mut bin_boxes: Vec<Option<AABB>> = vec![None; bins];
+        let mut bin_counts = vec![0; bins];
+        for (_, bbox) in data_and_boxes.iter() {
+            let i = bin_index(&bbox.center, axis);
+            bin_counts[i] += 1;
+            bin_boxes[i] = outer_optional(bin_boxes[i], Some(*bbox));
+        }
+
+        // Sweep from the back to get the cost of every second partition
+        let mut after_costs = vec![0.0; bins];
+        let mut after_box = None;
+        let mut after_count = 0;
+        for i in (1..bins).rev() {
+            after_box = outer_optional(after_box, bin_boxes[i]);
+            after_count += bin_counts[i];
+            after_costs[i] = after_box.map_or(0.0, |b: AABB| b.surface_area()) * after_count as f32;
+        }
+        // Sweep from the front and combine
+        let mut before_box = None;
+        let mut before_count = 0;
+        for i in 1..bins {
+            before_box = outer_optional(before_box, bin_boxes[i - 1]);
+            before_count += bin_counts[i - 1];
+            if before_count == 0 || before_count == data_and_boxes.len() {
+                continue;
+            }
+            let cost = before_box.map_or(0.0, |b: AABB| b.surface_area()) * before_count as f32
+                + after_costs[i];
+            if best_split.map_or(true, |best| cost < best.0) {
+                best_split = Some((cost, axis, i));
             }
         }
     }
-    else { 
-        let center = outer_box.min.z + (outer_box_dimensions/2.0).z;
-        data_and_boxes.sort_by(|a, b| { a.1.center.z.partial_cmp(&b.1.center.z).unwrap() });
-        for p in data_and_boxes.iter() {
-            if p.1.center.z <= center {
-                // TODO I should prevent the copies of data Members by 
-                before_split.push(p.clone());
-            } else {
-                after_split.push(p.clone());
-            }
+
+    match best_split {
+        Some((_, axis, split)) => data_and_boxes
+            .into_iter()
+            .partition(|(_, bbox)| bin_index(&bbox.center, axis) < split),
+        None => {
+            // All centers coincide, no bin boundary can separate them
+            let after_split = data_and_boxes.split_off(data_and_boxes.len() / 2);
+            (data_and_boxes, after_split)
         }
     }
+}
 
-    assert_eq!(before_split.len() + after_split.len(), data_and_boxes.len());
-    (before_split, after_split)
+fn outer_optional(a: Option<AABB>, b: Option<AABB>) -> Option<AABB> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(AABB::outer(&a, &b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
 }
 
 
@@ -320,8 +1383,262 @@ fn test_aabb_combine() {
     assert_eq!(AABB::outer(&a, &b), c);
 }
 #[test]
+fn test_aabb_ray_intersect() {
+    let a = AABB::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(3.0,3.0,3.0));
+    assert_eq!(a.ray_intersect(&Vec3::new(0.0, 2.0, 2.0), &Vec3::X), Some(1.0));
+    assert_eq!(a.ray_intersect(&Vec3::new(0.0, 2.0, 2.0), &(Vec3::X * 2.0)), Some(0.5));
+    assert_eq!(a.ray_intersect(&Vec3::splat(2.0), &Vec3::Y), Some(0.0));
+    assert_eq!(a.ray_intersect(&Vec3::new(0.0, 2.0, 2.0), &-Vec3::X), None);
+    assert_eq!(a.ray_intersect(&Vec3::new(0.0, 4.0, 2.0), &Vec3::X), None);
+}
+#[test]
+fn test_aabb_sweep() {
+    let moving = AABB::new(Vec3::splat(-0.5), Vec3::splat(0.5));
+    // A thin wall that a step of 10 would jump over
+    let wall = AABB::new(Vec3::new(4.5, -5.0, -5.0), Vec3::new(4.6, 5.0, 5.0));
+    let (toi, normal) = moving.sweep(&Vec3::new(10.0, 0.0, 0.0), &wall).unwrap();
+    assert!((toi - 0.4).abs() < 1e-5);
+    assert_eq!(normal, Vec3::new(-1.0, 0.0, 0.0));
+
+    // Diagonal motion hits the top of a floor
+    let floor = AABB::new(Vec3::new(-10.0, -3.0, -10.0), Vec3::new(10.0, -2.0, 10.0));
+    let (toi, normal) = moving.sweep(&Vec3::new(2.0, -3.0, 0.0), &floor).unwrap();
+    assert!((toi - 0.5).abs() < 1e-5);
+    assert_eq!(normal, Vec3::Y);
+
+    // Too short, moving away, passing by and already overlapping
+    assert!(moving.sweep(&Vec3::new(3.0, 0.0, 0.0), &wall).is_none());
+    assert!(moving.sweep(&Vec3::new(-10.0, 0.0, 0.0), &wall).is_none());
+    assert!(moving.sweep(&Vec3::new(10.0, 0.0, 0.0), &wall.translated(&Vec3::new(0.0, 11.0, 0.0))).is_none());
+    assert!(moving.sweep(&Vec3::new(1.0, 0.0, 0.0), &moving).is_none());
+
+    // Resting on the floor, or sunk in by less than the tolerance
+    for sunk in [0.0, SWEEP_TOLERANCE / 2.0] {
+        let resting = moving.translated(&Vec3::new(0.0, -1.5 - sunk, 0.0));
+        assert_eq!(resting.sweep(&Vec3::new(0.5, -0.1, 0.0), &floor), Some((0.0, Vec3::Y)));
+        // Free to lift off and to slide along it
+        assert!(resting.sweep(&Vec3::new(0.0, 0.1, 0.0), &floor).is_none());
+        assert!(resting.sweep(&Vec3::new(0.5, 0.0, 0.0), &floor).is_none());
+    }
+    assert!(moving.sweep(&Vec3::ZERO, &wall).is_none());
+}
+
+#[test]
+fn test_aabb_intersects() {
+    let a = AABB::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(3.0,3.0,3.0));
+    assert!(a.intersects(&AABB::new(Vec3::splat(2.0), Vec3::splat(4.0))));
+    assert!(a.intersects(&AABB::new(Vec3::splat(0.0), Vec3::splat(5.0))));
+    assert!(!a.intersects(&AABB::new(Vec3::new(2.0, 2.0, 4.0), Vec3::splat(5.0))));
+    assert!(a.intersects_sphere(&Vec3::new(4.0, 2.0, 2.0), 1.0));
+    assert!(!a.intersects_sphere(&Vec3::splat(4.0), 1.0));
+}
+#[test]
 fn test_aabb_contains() {
     let a = AABB::new(Vec3::new(1.0, 1.0, 1.0), Vec3::new(3.0,3.0,3.0));
     assert!(a.contains(&Vec3::splat(2.0)));
     assert!(!a.contains(&Vec3::splat(4.0)));
 }
+
+#[cfg(test)]
+fn test_count_visits_in_radius<T>(bvh: &BVH<T>, position: &Vec3, radius: f32) -> usize {
+    let mut visits = 0;
+    let mut stack = vec![0];
+    while let Some(index) = stack.pop() {
+        visits += 1;
+        if bvh.nodes[index].bbox.distance(position) <= radius && !bvh.nodes[index].is_leaf() {
+            let (left, right) = bvh.children(index);
+            stack.push(left);
+            stack.push(right);
+        }
+    }
+    visits
+}
+
+#[test]
+fn test_sah_improves_clustered_tree() {
+    let data_and_boxes = clustered_boxes();
+    let midpoint = BVH::create(data_and_boxes.clone()).unwrap();
+    let sah = BVH::create_with_options(
+        data_and_boxes.clone(),
+        &BVHBuildOptions { split_strategy: SplitStrategy::SurfaceAreaHeuristic { bins: 16 }, ..Default::default() },
+    ).unwrap();
+    assert!(sah.depth() < midpoint.depth());
+
+    let mut midpoint_visits = 0;
+    let mut sah_visits = 0;
+    for (_, bbox) in data_and_boxes.iter() {
+        midpoint_visits += test_count_visits_in_radius(&midpoint, &bbox.center, 0.05);
+        sah_visits += test_count_visits_in_radius(&sah, &bbox.center, 0.05);
+    }
+    assert!(sah_visits < midpoint_visits);
+
+    // Both trees still answer queries the same way
+    let position = Vec3::new(30.0, 0.1, 0.1);
+    assert_eq!(midpoint.get_n_closest(&position, 20), sah.get_n_closest(&position, 20));
+}
+
+#[test]
+fn test_leaf_buckets() {
+    fn check_leaf_sizes<T>(bvh: &BVH<T>, max_leaf_size: usize) {
+        for node in bvh.nodes.iter().filter(|node| node.is_leaf()) {
+            assert!(node.item_count <= max_leaf_size);
+            assert!(bvh.leaf_items(node).iter().all(|item| node.bbox.contains_aabb(&item.1)));
+        }
+    }
+    let data_and_boxes = clustered_boxes();
+    let single = BVH::create(data_and_boxes.clone()).unwrap();
+    let options = BVHBuildOptions { max_leaf_size: 8, ..Default::default() };
+    let bucketed = BVH::create_with_options(data_and_boxes.clone(), &options).unwrap();
+    check_leaf_sizes(&bucketed, 8);
+    assert!(bucketed.depth() < single.depth());
+
+    // Queries give the same answers as with one item per leaf
+    for position in [Vec3::new(30.0, 0.1, 0.1), Vec3::new(0.5, 0.0, 0.0), Vec3::splat(100.1)] {
+        assert_eq!(bucketed.get_n_closest(&position, 12), single.get_n_closest(&position, 12));
+        assert_eq!(bucketed.get_closest(&position).unwrap().1, single.get_closest(&position).unwrap().1);
+        let mut in_radius = bucketed.get_in_radius(&position, 0.2).unwrap_or_default();
+        let mut expected = single.get_in_radius(&position, 0.2).unwrap_or_default();
+        in_radius.sort();
+        expected.sort();
+        assert_eq!(in_radius, expected);
+    }
+    let origin = Vec3::new(-1.0, 0.1, 0.1);
+    assert_eq!(bucketed.raycast(&origin, &Vec3::X), single.raycast(&origin, &Vec3::X));
+    assert_eq!(bucketed.raycast_all(&origin, &Vec3::X), single.raycast_all(&origin, &Vec3::X));
+}
+
+#[cfg(feature = "parallel")]
+#[test]
+fn test_create_parallel_is_identical() {
+    let data_and_boxes = scattered_boxes(8 * PARALLEL_BUILD_THRESHOLD, [1013, 97, 89], 0.1, 0.05);
+    let strategies = [
+        SplitStrategy::Midpoint,
+        SplitStrategy::SurfaceAreaHeuristic { bins: 12 },
+    ];
+    for split_strategy in strategies {
+        for max_leaf_size in [1, 4] {
+            let options = BVHBuildOptions { split_strategy, max_leaf_size };
+            let sequential = BVH::create_with_options(data_and_boxes.clone(), &options).unwrap();
+            let parallel = BVH::create_parallel(data_and_boxes.clone(), &options).unwrap();
+            assert!(sequential == parallel);
+        }
+    }
+    // Small inputs take the sequential path directly
+    let small = test_construct_linear_boxes(5);
+    assert!(BVH::create(small.clone()) == BVH::create_parallel(small, &BVHBuildOptions::default()));
+}
+
+#[test]
+fn test_create_with_coinciding_centers() {
+    let bbox = AABB::new(Vec3::splat(0.0), Vec3::splat(1.0));
+    let data_and_boxes: Vec<(i32, AABB)> = (0..5).map(|i| (i, bbox)).collect();
+    assert!(BVH::create(data_and_boxes.clone()).is_some());
+    let sah = BVH::create_with_options(
+        data_and_boxes,
+        &BVHBuildOptions { split_strategy: SplitStrategy::SurfaceAreaHeuristic { bins: 8 }, ..Default::default() },
+    );
+    assert!(sah.is_some());
+}
+
+#[test]
+fn test_aabb_intersection() {
+    let a = AABB::new(Vec3::splat(0.0), Vec3::splat(2.0));
+    let b = AABB::new(Vec3::splat(1.0), Vec3::splat(3.0));
+    let c = AABB::new(Vec3::splat(4.0), Vec3::splat(5.0));
+    assert_eq!(a.intersection(&b), Some(AABB::new(Vec3::splat(1.0), Vec3::splat(2.0))));
+    assert_eq!(a.intersection(&b).unwrap().volume(), 1.0);
+    assert_eq!(a.intersection(&c), None);
+}
+
+#[test]
+fn test_stats() {
+    let root = BVH::create(test_construct_linear_boxes(5)).unwrap();
+    let stats = root.stats();
+    assert_eq!(stats.node_count, 9);
+    assert_eq!(stats.leaf_count, 5);
+    assert_eq!(stats.item_count, 5);
+    assert_eq!(stats.max_depth, root.depth());
+    assert!(stats.average_leaf_depth > 1.0 && stats.average_leaf_depth <= stats.max_depth as f32);
+    // The root is always entered and every item is tested at least once
+    assert!(stats.sah_cost > 1.0);
+    // The linear boxes are disjoint, so siblings never overlap
+    assert_eq!(stats.overlap_volume, 0.0);
+
+    let overlapping = BVH::create(test_construct_overlapping_boxes(64, 0.0)).unwrap();
+    assert!(overlapping.stats().overlap_volume > 0.0);
+}
+
+#[test]
+fn test_validate() {
+    let mut root = BVH::create(clustered_boxes()).unwrap();
+    assert_eq!(root.validate(), Ok(()));
+
+    // Move an item out of its leaf box
+    let bbox = root.items[0].1;
+    root.items[0].1 = bbox.translated(&Vec3::splat(1000.0));
+    assert!(root.validate().is_err());
+    root.items[0].1 = bbox;
+    assert_eq!(root.validate(), Ok(()));
+
+    // Indices that don't form a tree are reported instead of panicking
+    let right = root.nodes[0].offset;
+    root.nodes[0].offset = root.nodes.len();
+    assert!(root.validate().is_err());
+    root.nodes[0].offset = 1;
+    assert!(root.validate().is_err());
+    root.nodes[0].offset = right;
+    let leaf = root.nodes.iter().position(|node| node.is_leaf()).unwrap();
+    root.nodes[leaf].offset = root.items.len();
+    assert!(root.validate().is_err());
+    root.nodes[leaf].offset = usize::MAX;
+    assert!(root.validate().is_err());
+}
+
+#[test]
+fn test_write_obj() {
+    let root = BVH::create(test_construct_linear_boxes(5)).unwrap();
+    let levels = root.boxes_by_depth();
+    assert_eq!(levels.len(), root.depth());
+    assert_eq!(levels[0], vec![root.nodes[0].bbox]);
+    assert_eq!(levels.iter().map(|level| level.len()).sum::<usize>(), 9);
+
+    let mut obj = Vec::new();
+    root.write_obj(&mut obj).unwrap();
+    let obj = String::from_utf8(obj).unwrap();
+    assert_eq!(obj.lines().filter(|line| line.starts_with("g ")).count(), levels.len());
+    assert_eq!(obj.lines().filter(|line| line.starts_with("v ")).count(), 9 * 8);
+    assert_eq!(obj.lines().filter(|line| line.starts_with("l ")).count(), 9 * 12);
+    // The last edge of the last box, indices are 1-based
+    assert!(obj.ends_with("l 68 72\n"));
+}
+
+#[cfg(feature = "serialize")]
+#[test]
+fn test_binary_round_trip() {
+    let boxes = clustered_boxes();
+    let options = BVHBuildOptions {
+        split_strategy: SplitStrategy::SurfaceAreaHeuristic { bins: 8 },
+        max_leaf_size: 4,
+    };
+    let root = BVH::create_with_options(boxes, &options).unwrap();
+
+    let mut bytes = Vec::new();
+    root.write_binary(&mut bytes).unwrap();
+    let loaded: BVH<usize> = BVH::read_binary(bytes.as_slice()).unwrap();
+    assert_eq!(loaded, root);
+
+    let position = Vec3::new(30.0, 1.0, -2.0);
+    assert_eq!(loaded.get_closest(&position), root.get_closest(&position));
+    assert_eq!(loaded.get_n_closest(&position, 10), root.get_n_closest(&position, 10));
+    assert_eq!(loaded.get_in_radius(&position, 40.0), root.get_in_radius(&position, 40.0));
+    let direction = Vec3::new(-1.0, 0.05, 0.02);
+    assert_eq!(loaded.raycast_all(&Vec3::new(200.0, 0.0, 0.0), &direction),
+        root.raycast_all(&Vec3::new(200.0, 0.0, 0.0), &direction));
+
+    // Truncated snapshots fail to load instead of producing a partial tree
+    assert!(BVH::<usize>::read_binary(&bytes[..bytes.len() / 2]).is_err());
+    // So do snapshots that claim more nodes than fit into the size limit
+    let mut huge = bytes.clone();
+    huge[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert!(BVH::<usize>::read_binary(huge.as_slice()).is_err());
+}
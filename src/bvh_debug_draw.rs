@@ -0,0 +1,139 @@
+use std::marker::PhantomData;
+
+use bevy::{
+    prelude::*,
+    render::{mesh::PrimitiveTopology, view::NoFrustumCulling},
+};
+use crate::bvh::AABB;
+use crate::spatial_index::{SpatialIndex, SpatialIndexSystem};
+
+// Draws the boxes of the SpatialIndex<M> as wireframes, colour-coded by depth.
+// B toggles the overlay, N cycles through showing a single depth level and
+// all levels at once.
+pub struct BVHDebugDrawPlugin<M: Component> {
+    marker: PhantomData<M>,
+}
+
+impl<M: Component> Default for BVHDebugDrawPlugin<M> {
+    fn default() -> Self {
+        BVHDebugDrawPlugin { marker: PhantomData }
+    }
+}
+
+impl<M: Component> Plugin for BVHDebugDrawPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<BVHDebugDraw>()
+            .add_startup_system(spawn_debug_mesh)
+            .add_system(toggle_debug_draw)
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                update_debug_mesh::<M>.after(SpatialIndexSystem::Update),
+            );
+    }
+}
+
+#[derive(Resource, Debug, Default)]
+pub struct BVHDebugDraw {
+    pub enabled: bool,
+    // Only draw this depth level, all levels if None. The root is at depth 0
+    pub depth: Option<usize>,
+}
+
+#[derive(Component)]
+struct BVHDebugMesh;
+
+fn spawn_debug_mesh(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    commands
+        .spawn(PbrBundle {
+            mesh: meshes.add(Mesh::new(PrimitiveTopology::LineList)),
+            // White, so the vertex colours come through unchanged
+            material: materials.add(StandardMaterial {
+                base_color: Color::WHITE,
+                unlit: true,
+                ..Default::default()
+            }),
+            visibility: Visibility { is_visible: false },
+            ..Default::default()
+        })
+        .insert(BVHDebugMesh)
+        // The mesh changes with the tree, its bounds computed at spawn would be stale
+        .insert(NoFrustumCulling);
+}
+
+fn toggle_debug_draw(keys: Res<Input<KeyCode>>, mut debug_draw: ResMut<BVHDebugDraw>) {
+    if keys.just_pressed(KeyCode::B) {
+        debug_draw.enabled = !debug_draw.enabled;
+    }
+    if keys.just_pressed(KeyCode::N) {
+        // The upper bound is checked when drawing, a depth
+        // beyond the tree wraps around to all levels again
+        debug_draw.depth = match debug_draw.depth {
+            None => Some(0),
+            Some(depth) => Some(depth + 1),
+        };
+    }
+}
+
+fn update_debug_mesh<M: Component>(
+    mut debug_draw: ResMut<BVHDebugDraw>,
+    index: Res<SpatialIndex<M>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut query: Query<(&Handle<Mesh>, &mut Visibility), With<BVHDebugMesh>>,
+) {
+    // Only rebuilt when the tree or what to draw of it has changed
+    if !debug_draw.is_changed() && !index.is_changed() {
+        return;
+    }
+    for (_, mut visibility) in query.iter_mut() {
+        visibility.is_visible = debug_draw.enabled;
+    }
+    if !debug_draw.enabled {
+        return;
+    }
+    let levels = index.bvh().boxes_by_depth();
+    if debug_draw.depth.map_or(false, |depth| depth >= levels.len()) {
+        debug_draw.depth = None;
+    }
+    for (mesh_handle, _) in query.iter() {
+        let mut positions: Vec<[f32; 3]> = Vec::new();
+        let mut colors: Vec<[f32; 4]> = Vec::new();
+        for (depth, boxes) in levels.iter().enumerate() {
+            if debug_draw.depth.map_or(false, |shown| shown != depth) {
+                continue;
+            }
+            let color = depth_color(depth).as_rgba_f32();
+            for bbox in boxes {
+                push_wireframe(bbox, &mut positions);
+            }
+            colors.resize(positions.len(), color);
+        }
+        if let Some(mesh) = meshes.get_mut(mesh_handle) {
+            mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+            mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+        }
+    }
+}
+
+// Walks around the hue circle, so neighbouring levels are easy to tell apart
+fn depth_color(depth: usize) -> Color {
+    Color::hsl((depth as f32 * 47.0) % 360.0, 1.0, 0.5)
+}
+
+// Two vertices for each of the 12 edges of the box
+fn push_wireframe(bbox: &AABB, positions: &mut Vec<[f32; 3]>) {
+    let corners = bbox.corners();
+    for i in 0..8 {
+        // Every corner connects to the corners that differ in exactly one
+        // coordinate, only emitting edges towards max avoids duplicates
+        for axis in [1, 2, 4] {
+            if i & axis == 0 {
+                positions.push(corners[i].to_array());
+                positions.push(corners[i | axis].to_array());
+            }
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use bevy::prelude::*;
+use crate::bvh::AABB;
+
+// Fixtures shared by the tests of BVH and DynamicBVH. The brute force
+// references test every box, the trees have to return the same
+
+// Deterministic scatter of n cubes with edges of size. Their min corners lie
+// on a grid of grid[0] x grid[1] x grid[2] points, spacing apart
+pub fn scattered_boxes(n: usize, grid: [usize; 3], spacing: f32, size: f32) -> Vec<(usize, AABB)> {
+    (0..n).map(|i| {
+        let position = Vec3::new(
+            ((i * 37) % grid[0]) as f32,
+            ((i * 11) % grid[1]) as f32,
+            ((i * 5) % grid[2]) as f32,
+        ) * spacing;
+        (i, AABB::new(position, position + Vec3::splat(size)))
+    }).collect()
+}
+
+// Clusters at x = 100, 50, 25, ... so that the midpoint split can only ever
+// cut off one cluster at a time
+pub fn clustered_boxes() -> Vec<(usize, AABB)> {
+    let mut data_and_boxes = Vec::new();
+    for cluster in 0..8 {
+        let center = Vec3::new(100.0 / 2f32.powi(cluster), 0.0, 0.0);
+        for (_, bbox) in scattered_boxes(16, [17, 13, 7], 0.02, 0.01) {
+            data_and_boxes.push((data_and_boxes.len(), bbox.translated(&center)));
+        }
+    }
+    data_and_boxes
+}
+
+// The distances of the n closest boxes, sorted
+pub fn brute_force_n_closest(boxes: &[(usize, AABB)], position: &Vec3, n: usize) -> Vec<f32> {
+    let mut distances: Vec<f32> = boxes.iter().map(|(_, bbox)| bbox.distance(position)).collect();
+    distances.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    distances.truncate(n);
+    distances
+}
+
+// Sorted
+pub fn brute_force_in_radius(boxes: &[(usize, AABB)], position: &Vec3, radius: f32) -> Vec<usize> {
+    let mut data: Vec<usize> = boxes.iter()
+        .filter(|(_, bbox)| bbox.distance(position) <= radius)
+        .map(|(data, _)| *data)
+        .collect();
+    data.sort();
+    data
+}
+
+pub fn brute_force_raycast(boxes: &[(usize, AABB)], origin: &Vec3, direction: &Vec3) -> Option<(usize, f32)> {
+    boxes.iter()
+        .filter_map(|(data, bbox)| bbox.ray_intersect(origin, direction).map(|t| (*data, t)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+// With each pair sorted like the boxes. same compares a with itself,
+// each pair is only listed once then
+pub fn brute_force_pairs(a: &[(usize, AABB)], b: &[(usize, AABB)], same: bool) -> Vec<(usize, usize)> {
+    let mut pairs = Vec::new();
+    for (i, (data, bbox)) in a.iter().enumerate() {
+        for (j, (other_data, other_bbox)) in b.iter().enumerate() {
+            if same && j <= i {
+                continue;
+            }
+            if bbox.intersects(other_bbox) {
+                pairs.push((*data, *other_data));
+            }
+        }
+    }
+    pairs
+}
@@ -1,23 +1,131 @@
-use bevy::prelude::*;
-use std::collections::HashMap;
-use crate::bvh::BVHNode;
+use bevy::{prelude::*, render::mesh::VertexAttributeValues};
+use std::collections::{HashMap, HashSet};
+use crate::bvh::{AABB, BVH};
+use crate::narrowphase::{contact, ContactManifold, Shape};
+use crate::physics_schedule::{PhysicsSchedulePlugin, PhysicsStage, PhysicsSystem};
 
 pub struct CollisionDetectionPlugin;
 impl Plugin for CollisionDetectionPlugin {
     fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<PhysicsSchedulePlugin>() {
+            app.add_plugin(PhysicsSchedulePlugin);
+        }
         // I keep the collision data in a spatial hash 
         // to reduce the number of comparisons
         app.init_resource::<SpatialHash>()
+            .init_resource::<Broadphase>()
+            .init_resource::<CollisionPairs>()
+            .init_resource::<Contacts>()
+            .init_resource::<SensorPairs>()
+            .add_event::<CollisionStarted>()
+            .add_event::<CollisionOngoing>()
+            .add_event::<CollisionEnded>()
+            .add_event::<SensorEntered>()
+            .add_event::<SensorExited>()
             .add_startup_system(test_spawn_colliding_bodies)
+            // Collision detection runs once per physics substep,
+            // on the physics poses of this substep
+            // * Give entities with an AutoCollider a Collidable from their mesh
+            .add_system_to_stage(
+                PhysicsStage,
+                generate_auto_colliders
+                    .after(PhysicsSystem::Begin)
+                    .before(CollisionDetectionSystem::Broadphase)
+            )
             // * Copy collidable data into the spatial hash
-            .add_system(rebuild_spatial_hash)
+            .add_system_to_stage(
+                PhysicsStage,
+                rebuild_spatial_hash
+                    .label(CollisionDetectionSystem::Broadphase)
+                    .after(PhysicsSystem::Begin)
+            )
             // * Do the comparisons for each cell 
+            .add_system_to_stage(
+                PhysicsStage,
+                collision_detection
+                    .label(CollisionDetectionSystem::Broadphase)
+                    .after(rebuild_spatial_hash)
+            )
+            // Alternatively let a BVH find the overlapping pairs
+            .add_system_to_stage(
+                PhysicsStage,
+                bvh_collision_detection
+                    .label(CollisionDetectionSystem::Broadphase)
+                    .after(PhysicsSystem::Begin)
+            )
+            // * Drop pairs whose shapes don't touch and compute their contacts
+            .add_system_to_stage(
+                PhysicsStage,
+                narrowphase
+                    .label(CollisionDetectionSystem::Narrowphase)
+                    .after(CollisionDetectionSystem::Broadphase)
+                    .before(PhysicsSystem::End)
+            )
             // * write back to the ECS
-            .add_system(collision_detection)
-            .add_system(test_color_according_to_collision);
+            .add_system_to_stage(
+                PhysicsStage,
+                write_collisions
+                    .label(CollisionDetectionSystem::Events)
+                    .after(CollisionDetectionSystem::Narrowphase)
+            )
+            .add_system_to_stage(
+                PhysicsStage,
+                write_sensor_events
+                    .label(CollisionDetectionSystem::Events)
+                    .after(CollisionDetectionSystem::Narrowphase)
+            )
+            // The events are read in the Update of the next frame
+            .add_system(test_color_according_to_collision)
+            .add_system(test_log_sensor_events);
     }
 }
 
+// All of them run in PhysicsStage, once per physics substep. The pairs are
+// diffed against the previous substep, not the previous frame, so a frame
+// with several substeps can send several batches of events, e.g. a
+// CollisionStarted and a CollisionEnded for a brief touch. Systems that react
+// to the events run in Update and see those of the previous frame's substeps
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CollisionDetectionSystem {
+    Broadphase,
+    Narrowphase,
+    Events,
+}
+
+// Sent in the first substep two entities overlap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionStarted(pub Entity, pub Entity);
+
+// Sent in every following substep the entities still overlap
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionOngoing(pub Entity, pub Entity);
+
+// Sent in the first substep the entities don't overlap any more.
+// Also sent when one of them has been despawned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CollisionEnded(pub Entity, pub Entity);
+
+// Marks a Collidable as a trigger volume, e.g. a zone in the garden. Overlaps
+// with a sensor are reported as SensorEntered and SensorExited instead of the
+// Collision events, and they never get a contact manifold, so nothing
+// bounces off a sensor
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Sensor;
+
+// Sent in the first substep entity overlaps the sensor
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorEntered {
+    pub sensor: Entity,
+    pub entity: Entity,
+}
+
+// Sent in the first substep entity doesn't overlap the sensor any more
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SensorExited {
+    pub sensor: Entity,
+    pub entity: Entity,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct BoundingBox{
     min: Vec3,
@@ -44,17 +152,11 @@ impl BoundingBox {
         }
     }
 
+    // Encloses the rotated and scaled box, rotating only min and max
+    // doesn't give a valid box
     pub fn transformed(&self, transform: &Transform) -> BoundingBox {
-        self.translated(&transform.translation).rotated(&transform.rotation)
-        // Should I also scale the bounding box?
-    }
-    // Are these new allocations expensive?
-    pub fn translated(&self, translation: &Vec3) -> BoundingBox {
-        BoundingBox::new(self.min + *translation, self.max + *translation)
-    }
-
-    pub fn rotated(&self, rotation: &Quat) -> BoundingBox {
-        BoundingBox::new(rotation.mul_vec3(self.min), rotation.mul_vec3(self.max))
+        let aabb = AABB::new(self.min, self.max).transformed(transform);
+        BoundingBox::new(aabb.min(), aabb.max())
     }
 }
 
@@ -113,70 +215,312 @@ fn intersects(box_1: &BoundingBox, box_2: &BoundingBox) -> bool {
     return true;
 }
 
+// Which data structure finds the candidate pairs
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub enum Broadphase {
+    SpatialHash,
+    BVH,
+}
+
+impl Default for Broadphase {
+    fn default() -> Broadphase {
+        Broadphase::SpatialHash
+    }
+}
+
+// Integer coordinates of a grid cell, cell (0, 0, 0) spans [0, cell_length) on every axis
+type Cell = (i32, i32, i32);
+
+#[derive(Resource)]
 struct SpatialHash {
-    hash: HashMap<(u16, u16, u16), HashMap<Entity, (Collidable, Transform)>>,
-    // Make sure the cell_length is larger than the biggest BoundingBox
+    hash: HashMap<Cell, HashMap<Entity, (Collidable, Transform)>>,
+    // Boxes are inserted into every cell they touch, so the cell_length
+    // should be around the size of a typical BoundingBox. Much smaller
+    // cells make big boxes expensive to insert
     cell_length: f32,
+    // Boxes that span more than MAX_CELLS_PER_BOX cells, like the ground or a
+    // box that blew up. They are kept out of the cells and compared with
+    // every other entity instead
+    oversized: HashMap<Entity, (Collidable, Transform)>,
 }
 
+const MAX_CELLS_PER_BOX: i64 = 512;
+
 impl Default for SpatialHash {
     fn default() -> SpatialHash {
         SpatialHash{
             hash: HashMap::new(),
             cell_length: 1.0,
+            oversized: HashMap::new(),
         }
     }
 }
 
 impl SpatialHash {
 
-    fn is_empty(&self) -> bool {
-        self.hash.is_empty()
-    }
-
     fn clear(&mut self) {
         self.hash.clear();
+        self.oversized.clear();
+    }
+
+    // floor instead of a cast, which would truncate towards zero
+    // and put -0.5 and 0.5 into the same cell
+    fn cell(&self, position: &Vec3) -> Cell {
+        (
+            (position.x / self.cell_length).floor() as i32,
+            (position.y / self.cell_length).floor() as i32,
+            (position.z / self.cell_length).floor() as i32,
+        )
+    }
+
+    // All cells overlapped by the box, not only the ones its corners are in.
+    // None if those are more than MAX_CELLS_PER_BOX
+    fn cells_spanned(&self, bb: &BoundingBox) -> Option<Vec<Cell>> {
+        let min = self.cell(&bb.min);
+        let max = self.cell(&bb.max);
+        let count = (max.0 as i64 - min.0 as i64 + 1)
+            .saturating_mul(max.1 as i64 - min.1 as i64 + 1)
+            .saturating_mul(max.2 as i64 - min.2 as i64 + 1);
+        if count > MAX_CELLS_PER_BOX {
+            return None;
+        }
+        let mut cells = Vec::new();
+        for x in min.0..=max.0 {
+            for y in min.1..=max.1 {
+                for z in min.2..=max.2 {
+                    cells.push((x, y, z));
+                }
+            }
+        }
+        Some(cells)
     }
 
     fn insert(&mut self, entity: Entity, collidable: Collidable, transform: Transform) {
-        // Discretize all corners of the bounding box
-        for corner in collidable.bounding_box.transformed(&transform) {
-            // Add them to every unique GridCell
-            let discretized_position = (
-                (corner.x / self.cell_length).round() as u16,
-                (corner.y / self.cell_length).round() as u16,
-                (corner.z / self.cell_length).round() as u16,
-            );
-            if let Some(set) = self.hash.get_mut(&discretized_position) {
-                set.insert(entity, (collidable.clone(), transform));
-            } else {
-                let mut map = HashMap::new();
-                map.insert(entity, (collidable.clone(), transform));
-                self.hash.insert(discretized_position, map);
+        let bb = collidable.bounding_box.transformed(&transform);
+        match self.cells_spanned(&bb) {
+            Some(cells) => {
+                for cell in cells {
+                    self.hash
+                        .entry(cell)
+                        .or_insert_with(HashMap::new)
+                        .insert(entity, (collidable.clone(), transform));
+                }
+            }
+            None => {
+                self.oversized.insert(entity, (collidable, transform));
             }
         }
     }
+
+    // Each cell's entities are grouped by their layers first, so entities
+    // that can't collide, like two snowflakes, are never compared at all
+    fn overlapping_pairs(&self) -> Vec<(Entity, Entity)> {
+        let mut pairs = Vec::new();
+        for map in self.hash.values() {
+            if map.len() < 2 {
+                continue;
+            }
+            let groups = group_by_layers(map.iter().map(|(entity, (collidable, transform))| {
+                (collidable.layers(), (*entity, collidable.bounding_box.transformed(transform)))
+            }));
+            for (i, (layers, group)) in groups.iter().enumerate() {
+                if layers_match(layers, layers) {
+                    for (j, (entity, bb)) in group.iter().enumerate() {
+                        for (other_entity, other_bb) in &group[j + 1..] {
+                            if intersects(bb, other_bb) {
+                                pairs.push((*entity, *other_entity));
+                            }
+                        }
+                    }
+                }
+                for (other_layers, other_group) in &groups[i + 1..] {
+                    if !layers_match(layers, other_layers) {
+                        continue;
+                    }
+                    for (entity, bb) in group.iter() {
+                        for (other_entity, other_bb) in other_group.iter() {
+                            if intersects(bb, other_bb) {
+                                pairs.push((*entity, *other_entity));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // Oversized boxes against everything, including each other once
+        let mut others: HashMap<Entity, &(Collidable, Transform)> = HashMap::new();
+        for map in self.hash.values() {
+            others.extend(map.iter().map(|(entity, entry)| (*entity, entry)));
+        }
+        for (entity, (collidable, transform)) in self.oversized.iter() {
+            let bb = collidable.bounding_box.transformed(transform);
+            let candidates = others.iter()
+                .map(|(other_entity, entry)| (*other_entity, *entry))
+                .chain(self.oversized.iter()
+                    .filter(|(other_entity, _)| *other_entity < entity)
+                    .map(|(other_entity, entry)| (*other_entity, entry)));
+            for (other_entity, (other_collidable, other_transform)) in candidates {
+                if collidable.can_collide(other_collidable)
+                    && intersects(&bb, &other_collidable.bounding_box.transformed(other_transform)) {
+                    pairs.push((*entity, other_entity));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+// Bits for Collidable::layer and collides_with_mask
+pub const DEFAULT_LAYER: u32 = 1 << 0;
+pub const SNOW_LAYER: u32 = 1 << 1;
+pub const ALL_LAYERS: u32 = u32::MAX;
+
+// (layer, collides_with_mask) of a Collidable
+type Layers = (u32, u32);
+
+fn layers_match(a: &Layers, b: &Layers) -> bool {
+    a.0 & b.1 != 0 && b.0 & a.1 != 0
+}
+
+// Collidables with the same layers either all can collide with another
+// group or none of them can, so the broadphases compare whole groups
+fn group_by_layers<T>(items: impl Iterator<Item = (Layers, T)>) -> Vec<(Layers, Vec<T>)> {
+    let mut groups: Vec<(Layers, Vec<T>)> = Vec::new();
+    for (layers, item) in items {
+        match groups.iter_mut().find(|(group_layers, _)| *group_layers == layers) {
+            Some((_, group)) => group.push(item),
+            None => groups.push((layers, vec![item])),
+        }
+    }
+    groups
 }
 
-#[derive(Clone, Default)]
+#[derive(Component, Clone)]
 pub struct Collidable {
    bounding_box: BoundingBox,
+   // Without a shape, overlapping bounding boxes count as a collision
+   shape: Option<Shape>,
+   // Two Collidables are only tested against each other if each one's
+   // layer is in the other's collides_with_mask
+   layer: u32,
+   collides_with_mask: u32,
    collides_with: Vec<Entity> 
 }
 
+impl Default for Collidable {
+    fn default() -> Collidable {
+        Collidable::new(BoundingBox::default())
+    }
+}
+
 impl Collidable {
     pub fn new(bounding_box: BoundingBox) -> Collidable {
         Collidable {
             bounding_box,
+            shape: None,
+            layer: DEFAULT_LAYER,
+            collides_with_mask: ALL_LAYERS,
             collides_with: Vec::new(),
         }
     }
 
+    // The bounding box is taken from the shape
+    pub fn from_shape(shape: Shape) -> Collidable {
+        let bbox = shape.bounding_box();
+        Collidable {
+            shape: Some(shape),
+            ..Collidable::new(BoundingBox::new(bbox.min(), bbox.max()))
+        }
+    }
+
+    pub fn with_layers(self, layer: u32, collides_with_mask: u32) -> Collidable {
+        Collidable {
+            layer,
+            collides_with_mask,
+            ..self
+        }
+    }
+
+    pub fn world_aabb(&self, transform: &Transform) -> AABB {
+        let bb = self.bounding_box.transformed(transform);
+        AABB::new(bb.min, bb.max)
+    }
+
+    pub fn can_collide(&self, other: &Collidable) -> bool {
+        layers_match(&self.layers(), &other.layers())
+    }
+
+    fn layers(&self) -> Layers {
+        (self.layer, self.collides_with_mask)
+    }
+
     pub fn collides(&self) -> bool {
         self.collides_with.len() != 0
     }
 }
 
+// Opt-in marker for entities with a Handle<Mesh>. Once the mesh has loaded,
+// the entity gets a Collidable that encloses its vertices and the marker is
+// removed. An existing Collidable keeps its layers, only its bounds are replaced
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub enum AutoCollider {
+    // The bounding box of the vertices
+    BoundingBox,
+    // A sphere around the origin of the mesh
+    Sphere,
+}
+
+impl Default for AutoCollider {
+    fn default() -> AutoCollider {
+        AutoCollider::BoundingBox
+    }
+}
+
+fn bounding_box_of(positions: &[[f32; 3]]) -> Option<BoundingBox> {
+    let first = Vec3::from(*positions.first()?);
+    let (min, max) = positions.iter().fold((first, first), |(min, max), position| {
+        (min.min(Vec3::from(*position)), max.max(Vec3::from(*position)))
+    });
+    Some(BoundingBox::new(min, max))
+}
+
+fn bounding_radius_of(positions: &[[f32; 3]]) -> Option<f32> {
+    positions.iter().map(|position| Vec3::from(*position).length()).reduce(f32::max)
+}
+
+fn generate_auto_colliders(
+    mut commands: Commands,
+    meshes: Res<Assets<Mesh>>,
+    mut query: Query<(Entity, &Handle<Mesh>, &AutoCollider, Option<&mut Collidable>)>,
+) {
+    for (entity, mesh_handle, auto_collider, collidable) in query.iter_mut() {
+        let mesh = match meshes.get(mesh_handle) {
+            Some(mesh) => mesh,
+            // Not loaded yet, try again next frame
+            None => continue,
+        };
+        let positions: &[[f32; 3]] = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => &[],
+        };
+        let generated = match auto_collider {
+            AutoCollider::BoundingBox => bounding_box_of(positions).map(Collidable::new),
+            AutoCollider::Sphere => bounding_radius_of(positions)
+                .map(|radius| Collidable::from_shape(Shape::Sphere { radius })),
+        };
+        match (generated, collidable) {
+            (Some(generated), Some(mut collidable)) => {
+                collidable.bounding_box = generated.bounding_box;
+                collidable.shape = generated.shape;
+            }
+            (Some(generated), None) => { commands.entity(entity).insert(generated); }
+            (None, _) => warn!("Can't generate a collider for {:?}, its mesh has no vertex positions", entity),
+        }
+        commands.entity(entity).remove::<AutoCollider>();
+    }
+}
+
 fn _update_spatial_hash(
     mut spatial_hash: ResMut<SpatialHash>,
     mut query: Query<(Entity, &mut Collidable, &Transform), Changed<Transform>>,
@@ -190,6 +534,7 @@ fn _update_spatial_hash(
         for (_, map) in spatial_hash.hash.iter_mut() {
             map.remove(&entity);
         }
+        spatial_hash.oversized.remove(&entity);
 
         // Past collision might not be relevant any more
         collidable.collides_with.clear();
@@ -199,58 +544,218 @@ fn _update_spatial_hash(
 }
 
 fn rebuild_spatial_hash(
+    broadphase: Res<Broadphase>,
     mut spatial_hash: ResMut<SpatialHash>,
-    mut query: Query<(Entity, &mut Collidable, &Transform)>,
+    query: Query<(Entity, &Collidable, &Transform)>,
 ) {
+    if *broadphase != Broadphase::SpatialHash {
+        return;
+    }
     spatial_hash.clear();
-    for (entity, mut collidable, transform) in query.iter_mut() {
-        collidable.collides_with.clear();
+    for (entity, collidable, transform) in query.iter() {
         // Add entities to the Hash, maybe I can get rid of the clone?
         spatial_hash.insert(entity, collidable.clone(), transform.clone());
     }
-    assert!(!spatial_hash.is_empty());
+}
+
+// The overlapping pairs of this and the previous substep. The smaller entity
+// comes first, so a pair is stored once even if it is found in several cells
+#[derive(Resource, Default)]
+struct CollisionPairs {
+    current: HashSet<(Entity, Entity)>,
+    previous: HashSet<(Entity, Entity)>,
+}
+
+impl CollisionPairs {
+    fn insert(&mut self, a: Entity, b: Entity) {
+        self.current.insert((a.min(b), a.max(b)));
+    }
+
+    // Pairs that started, are ongoing and ended since the previous substep
+    fn diff(&self) -> (Vec<(Entity, Entity)>, Vec<(Entity, Entity)>, Vec<(Entity, Entity)>) {
+        let started = self.current.difference(&self.previous).cloned().collect();
+        let ongoing = self.current.intersection(&self.previous).cloned().collect();
+        let ended = self.previous.difference(&self.current).cloned().collect();
+        (started, ongoing, ended)
+    }
+
+    // Make this substep's pairs the previous ones and start collecting anew
+    fn advance(&mut self) {
+        self.previous = std::mem::take(&mut self.current);
+    }
 }
 
 fn collision_detection(
-    mut spatial_hash: ResMut<SpatialHash>,
-    // TODO I think I could reduce this to only entities whos transform has changed
-    mut query: Query<(Entity, &mut Collidable, &Transform)>,
+    broadphase: Res<Broadphase>,
+    spatial_hash: Res<SpatialHash>,
+    mut pairs: ResMut<CollisionPairs>,
+) {
+    if *broadphase != Broadphase::SpatialHash {
+        return;
+    }
+    // Pairs that share several cells are found repeatedly,
+    // the set only keeps them once
+    for (entity, other_entity) in spatial_hash.overlapping_pairs() {
+        pairs.insert(entity, other_entity);
+    }
+}
+
+fn bvh_collision_detection(
+    broadphase: Res<Broadphase>,
+    mut pairs: ResMut<CollisionPairs>,
+    query: Query<(Entity, &Collidable, &Transform)>,
 ) {
-    // For each cell
-    for map in spatial_hash.hash.values_mut() {
-        assert!(!map.is_empty());
-        if map.len() < 2 {
-            continue;
+    if *broadphase != Broadphase::BVH {
+        return;
+    }
+    for (entity, other_entity) in bvh_overlapping_pairs(query.iter()) {
+        pairs.insert(entity, other_entity);
+    }
+}
+
+// One tree per group of layers, groups that can't collide are never walked
+// against each other. The trees walk themselves to find every overlapping
+// pair exactly once
+fn bvh_overlapping_pairs<'a>(
+    collidables: impl Iterator<Item = (Entity, &'a Collidable, &'a Transform)>,
+) -> Vec<(Entity, Entity)> {
+    let groups = group_by_layers(collidables.map(|(entity, collidable, transform)| {
+        (collidable.layers(), (entity, collidable.world_aabb(transform)))
+    }));
+    let trees: Vec<(Layers, BVH<Entity>)> = groups.into_iter()
+        .filter_map(|(layers, group)| BVH::create(group).map(|tree| (layers, tree)))
+        .collect();
+    let mut pairs = Vec::new();
+    for (i, (layers, tree)) in trees.iter().enumerate() {
+        if layers_match(layers, layers) {
+            pairs.extend(tree.overlapping_pairs());
         }
-        // I think I need this copy to iterate over the entities with defined order
-        let keys: Vec<Entity> = map.keys().map(|e| e.clone()).collect();
-        let length = keys.len();
-        assert!(length >= 2);
-        // Compare elements in the cell with each other
-        for (i, entity) in keys.iter().enumerate() {
-            let (collidable, transform) = map.get_mut(entity).unwrap();
-            let bb = collidable.bounding_box.transformed(transform);
-            for j in i+1..length {
-                let other_entity = keys[j];
-                let (other_collidable, other_transform) = map.get_mut(&other_entity).unwrap();
-                let other_bb = other_collidable.bounding_box.transformed(&other_transform);
-                let intersects = intersects(&bb, &other_bb);
-                if intersects {
-                    // Mark it in the collidable
-                    other_collidable.collides_with.push(*entity);
-                }
-                // println!("Comparing boxes: {:?} and {:?} -> Collision: {}", bb, other_bb, intersects);
+        for (other_layers, other_tree) in &trees[i + 1..] {
+            if layers_match(layers, other_layers) {
+                pairs.extend(tree.overlapping_pairs_with(other_tree));
             }
         }
-        // Write collisions back. I'm not sure if I should use a HashSet<Entity> for collides_with, because right now entities can be in there multiple times
-        for (e, (local_collidable, _)) in map.iter() {
-            if let Ok(mut collidable) = query.get_component_mut::<Collidable>(*e) {
-                for colliding_entity in local_collidable.collides_with.iter() {
-                    collidable.collides_with.push(*colliding_entity);
+    }
+    pairs
+}
+
+// Contact manifolds of the colliding pairs where both entities have a shape,
+// as found in the latest physics substep.
+// Keyed like CollisionPairs, with the normal pointing from the first to the second entity
+#[derive(Resource, Default)]
+pub struct Contacts {
+    manifolds: HashMap<(Entity, Entity), ContactManifold>,
+}
+
+impl Contacts {
+    // The normal of the manifold points from a to b
+    pub fn get(&self, a: Entity, b: Entity) -> Option<ContactManifold> {
+        if a < b {
+            self.manifolds.get(&(a, b)).cloned()
+        } else {
+            self.manifolds.get(&(b, a)).cloned().map(ContactManifold::flipped)
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&(Entity, Entity), &ContactManifold)> {
+        self.manifolds.iter()
+    }
+}
+
+// Overlaps with sensors, as (sensor, entity). If two sensors overlap,
+// the pair is stored both ways round
+#[derive(Resource, Default)]
+struct SensorPairs(CollisionPairs);
+
+impl SensorPairs {
+    // The events since the previous diff, starts collecting anew
+    fn advance(&mut self) -> (Vec<SensorEntered>, Vec<SensorExited>) {
+        let (entered, _, exited) = self.0.diff();
+        self.0.advance();
+        (
+            entered.into_iter().map(|(sensor, entity)| SensorEntered { sensor, entity }).collect(),
+            exited.into_iter().map(|(sensor, entity)| SensorExited { sensor, entity }).collect(),
+        )
+    }
+}
+
+// Also moves the pairs with a sensor over to the SensorPairs
+fn narrowphase(
+    mut pairs: ResMut<CollisionPairs>,
+    mut sensor_pairs: ResMut<SensorPairs>,
+    mut contacts: ResMut<Contacts>,
+    query: Query<(&Collidable, &Transform, Option<&Sensor>)>,
+) {
+    contacts.manifolds.clear();
+    let mut removed = Vec::new();
+    for (a, b) in pairs.current.iter() {
+        if let (Ok((collidable_a, transform_a, sensor_a)), Ok((collidable_b, transform_b, sensor_b)))
+            = (query.get(*a), query.get(*b)) {
+            let manifold = match (&collidable_a.shape, &collidable_b.shape) {
+                (Some(shape_a), Some(shape_b)) => match contact(shape_a, transform_a, shape_b, transform_b) {
+                    Some(manifold) => Some(manifold),
+                    // Only the bounding boxes overlap
+                    None => {
+                        removed.push((*a, *b));
+                        continue;
+                    }
+                },
+                _ => None,
+            };
+            if sensor_a.is_some() || sensor_b.is_some() {
+                if sensor_a.is_some() {
+                    sensor_pairs.0.current.insert((*a, *b));
                 }
+                if sensor_b.is_some() {
+                    sensor_pairs.0.current.insert((*b, *a));
+                }
+                removed.push((*a, *b));
+            } else if let Some(manifold) = manifold {
+                contacts.manifolds.insert((*a, *b), manifold);
             }
         }
     }
+    for pair in removed {
+        pairs.current.remove(&pair);
+    }
+}
+
+fn write_sensor_events(
+    mut sensor_pairs: ResMut<SensorPairs>,
+    mut entered_events: EventWriter<SensorEntered>,
+    mut exited_events: EventWriter<SensorExited>,
+) {
+    let (entered, exited) = sensor_pairs.advance();
+    entered_events.send_batch(entered);
+    exited_events.send_batch(exited);
+}
+
+// Marks both sides of every pair in their Collidable and sends the events
+// of this substep
+fn write_collisions(
+    mut pairs: ResMut<CollisionPairs>,
+    mut query: Query<&mut Collidable>,
+    mut started_events: EventWriter<CollisionStarted>,
+    mut ongoing_events: EventWriter<CollisionOngoing>,
+    mut ended_events: EventWriter<CollisionEnded>,
+) {
+    for mut collidable in query.iter_mut() {
+        collidable.collides_with.clear();
+    }
+    for (a, b) in pairs.current.iter() {
+        if let Ok(mut collidable) = query.get_mut(*a) {
+            collidable.collides_with.push(*b);
+        }
+        if let Ok(mut collidable) = query.get_mut(*b) {
+            collidable.collides_with.push(*a);
+        }
+    }
+
+    let (started, ongoing, ended) = pairs.diff();
+    started_events.send_batch(started.into_iter().map(|(a, b)| CollisionStarted(a, b)));
+    ongoing_events.send_batch(ongoing.into_iter().map(|(a, b)| CollisionOngoing(a, b)));
+    ended_events.send_batch(ended.into_iter().map(|(a, b)| CollisionEnded(a, b)));
+    pairs.advance();
 }
 
 fn test_color_according_to_collision(
@@ -286,17 +791,46 @@ fn test_spawn_colliding_bodies(
     
     for t in transforms.iter() {
         commands
-        .spawn()
-        .insert_bundle(PbrBundle {
+        .spawn(PbrBundle {
             mesh: mesh.clone(),
             material: green_material.clone(),
             transform: t.clone(),
             ..Default::default()
         })
-        .insert(Collidable::new(
-            BoundingBox::default()
+        .insert(Collidable::from_shape(
+            Shape::Box { half_extents: Vec3::splat(0.5) }
         ));
     }
+
+    // An invisible zone around the first two boxes
+    commands
+        .spawn(TransformBundle::from_transform(Transform::from_translation(Vec3::new(1.0, 1.0, 1.0))))
+        .insert(Collidable::from_shape(Shape::Box { half_extents: Vec3::splat(1.5) }))
+        .insert(Sensor);
+}
+
+fn test_log_sensor_events(
+    mut entered_events: EventReader<SensorEntered>,
+    mut exited_events: EventReader<SensorExited>,
+) {
+    for event in entered_events.iter() {
+        info!("{:?} entered sensor {:?}", event.entity, event.sensor);
+    }
+    for event in exited_events.iter() {
+        info!("{:?} left sensor {:?}", event.entity, event.sensor);
+    }
+}
+
+#[test]
+fn test_bounding_box_transformed() {
+    let transform = Transform::from_translation(Vec3::splat(2.0))
+        .with_rotation(Quat::from_rotation_y(std::f32::consts::PI))
+        .with_scale(Vec3::splat(2.0));
+    let bb = BoundingBox::default().transformed(&transform);
+    // A half turn flips min and max, the box has to stay valid
+    assert!(bb.min.cmplt(bb.max).all());
+    assert!((bb.min - Vec3::splat(1.0)).abs().max_element() < 1e-5);
+    assert!((bb.max - Vec3::splat(3.0)).abs().max_element() < 1e-5);
 }
 
 #[test]
@@ -325,3 +859,173 @@ fn test_intersects_separate_boxes_2() {
     assert!(!intersects(&box_1, &box_2))
 }
 
+#[cfg(test)]
+fn test_entity(index: u32) -> Entity {
+    Entity::from_raw(index)
+}
+
+#[test]
+fn test_spatial_hash_negative_coordinates() {
+    let mut spatial_hash = SpatialHash::default();
+    assert_eq!(spatial_hash.cell(&Vec3::new(-0.5, 0.5, -2.5)), (-1, 0, -3));
+
+    // Two boxes left of the origin that are far apart must not share a cell
+    let left = Collidable::new(BoundingBox::new(Vec3::splat(-0.2), Vec3::splat(0.2)));
+    spatial_hash.insert(test_entity(0), left.clone(), Transform::from_translation(Vec3::new(-5.5, 0.5, 0.5)));
+    spatial_hash.insert(test_entity(1), left, Transform::from_translation(Vec3::new(-10.5, 0.5, 0.5)));
+    assert_eq!(spatial_hash.hash.len(), 2);
+    assert!(spatial_hash.hash[&(-6, 0, 0)].contains_key(&test_entity(0)));
+    assert!(spatial_hash.hash[&(-11, 0, 0)].contains_key(&test_entity(1)));
+}
+
+#[test]
+fn test_spatial_hash_box_spanning_several_cells() {
+    let mut spatial_hash = SpatialHash::default();
+    // Spans cells -1..=2 on x, 0 on y and z, the corners only touch -1 and 2
+    let wide = Collidable::new(BoundingBox::new(Vec3::new(-0.5, 0.1, 0.1), Vec3::new(2.5, 0.9, 0.9)));
+    spatial_hash.insert(test_entity(0), wide, Transform::default());
+    for x in -1..=2 {
+        assert!(spatial_hash.hash[&(x, 0, 0)].contains_key(&test_entity(0)));
+    }
+    assert_eq!(spatial_hash.hash.len(), 4);
+
+    // A small box in a middle cell has to find the wide one
+    let small = Collidable::new(BoundingBox::new(Vec3::splat(0.2), Vec3::splat(0.4)));
+    spatial_hash.insert(test_entity(1), small, Transform::from_translation(Vec3::new(1.0, 0.0, 0.0)));
+    assert_eq!(spatial_hash.hash[&(1, 0, 0)].len(), 2);
+}
+
+#[test]
+fn test_spatial_hash_oversized_boxes() {
+    let mut spatial_hash = SpatialHash::default();
+    // Would span 10^9 cells, it is kept out of them
+    let huge = Collidable::new(BoundingBox::new(Vec3::splat(-500.0), Vec3::splat(500.0)));
+    spatial_hash.insert(test_entity(0), huge.clone(), Transform::default());
+    assert!(spatial_hash.hash.is_empty());
+    spatial_hash.insert(test_entity(1), huge, Transform::from_translation(Vec3::splat(1200.0)));
+    spatial_hash.insert(test_entity(2), Collidable::default(), Transform::default());
+    spatial_hash.insert(test_entity(3), Collidable::default(), Transform::from_translation(Vec3::splat(1000.0)));
+    assert_eq!(spatial_hash.oversized.len(), 2);
+
+    // Still found against the small boxes in the cells and each other
+    let mut pairs: Vec<(Entity, Entity)> = spatial_hash.overlapping_pairs()
+        .into_iter()
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    pairs.sort();
+    assert_eq!(pairs, vec![
+        (test_entity(0), test_entity(2)),
+        (test_entity(1), test_entity(3)),
+    ]);
+}
+
+#[test]
+fn test_collision_pairs_diff() {
+    let mut pairs = CollisionPairs::default();
+    pairs.insert(test_entity(1), test_entity(0));
+    // The same pair found again, in the other order
+    pairs.insert(test_entity(0), test_entity(1));
+    pairs.insert(test_entity(2), test_entity(3));
+    let (started, ongoing, ended) = pairs.diff();
+    assert_eq!(started.len(), 2);
+    assert!(started.contains(&(test_entity(0), test_entity(1))));
+    assert!(ongoing.is_empty() && ended.is_empty());
+
+    pairs.advance();
+    pairs.insert(test_entity(0), test_entity(1));
+    pairs.insert(test_entity(3), test_entity(4));
+    let (started, ongoing, ended) = pairs.diff();
+    assert_eq!(started, vec![(test_entity(3), test_entity(4))]);
+    assert_eq!(ongoing, vec![(test_entity(0), test_entity(1))]);
+    assert_eq!(ended, vec![(test_entity(2), test_entity(3))]);
+
+    pairs.advance();
+    let (started, ongoing, ended) = pairs.diff();
+    assert!(started.is_empty() && ongoing.is_empty());
+    assert_eq!(ended.len(), 2);
+}
+
+#[test]
+fn test_sensor_events() {
+    let sensor = test_entity(0);
+    let ball = test_entity(1);
+    let mut sensor_pairs = SensorPairs::default();
+    sensor_pairs.0.current.insert((sensor, ball));
+    assert_eq!(sensor_pairs.advance(), (vec![SensorEntered { sensor, entity: ball }], vec![]));
+    // Staying inside sends nothing
+    sensor_pairs.0.current.insert((sensor, ball));
+    assert_eq!(sensor_pairs.advance(), (vec![], vec![]));
+    assert_eq!(sensor_pairs.advance(), (vec![], vec![SensorExited { sensor, entity: ball }]));
+    assert_eq!(sensor_pairs.advance(), (vec![], vec![]));
+}
+
+#[test]
+fn test_collision_layers() {
+    let ground = Collidable::default();
+    let snowflake = Collidable::default().with_layers(SNOW_LAYER, ALL_LAYERS & !SNOW_LAYER);
+    assert!(ground.can_collide(&ground));
+    assert!(snowflake.can_collide(&ground));
+    assert!(ground.can_collide(&snowflake));
+    assert!(!snowflake.can_collide(&snowflake));
+
+    // Both sides have to agree
+    let ignores_snow = Collidable::default().with_layers(DEFAULT_LAYER, DEFAULT_LAYER);
+    assert!(!snowflake.can_collide(&ignores_snow));
+    assert!(!ignores_snow.can_collide(&snowflake));
+}
+
+#[test]
+fn test_spatial_hash_skips_layers_that_dont_match() {
+    let mut spatial_hash = SpatialHash::default();
+    assert!(spatial_hash.overlapping_pairs().is_empty());
+
+    // Everything overlaps
+    let snowflake = Collidable::default().with_layers(SNOW_LAYER, ALL_LAYERS & !SNOW_LAYER);
+    let position = Transform::from_translation(Vec3::splat(0.5));
+    spatial_hash.insert(test_entity(0), Collidable::default(), position);
+    spatial_hash.insert(test_entity(1), snowflake.clone(), position);
+    spatial_hash.insert(test_entity(2), snowflake, position);
+    let mut pairs: Vec<(Entity, Entity)> = spatial_hash.overlapping_pairs()
+        .into_iter()
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    // The boxes share several cells, every cell finds the pairs again
+    pairs.sort();
+    pairs.dedup();
+    // Both snowflakes hit the ground, but not each other
+    assert_eq!(pairs, vec![(test_entity(0), test_entity(1)), (test_entity(0), test_entity(2))]);
+}
+
+#[test]
+fn test_bvh_skips_layers_that_dont_match() {
+    let position = Transform::from_translation(Vec3::splat(0.5));
+    assert!(bvh_overlapping_pairs(std::iter::empty()).is_empty());
+
+    // Everything overlaps
+    let ground = Collidable::default();
+    let snowflake = Collidable::default().with_layers(SNOW_LAYER, ALL_LAYERS & !SNOW_LAYER);
+    let collidables = [
+        (test_entity(0), &ground, &position),
+        (test_entity(1), &snowflake, &position),
+        (test_entity(2), &snowflake, &position),
+    ];
+    let mut pairs: Vec<(Entity, Entity)> = bvh_overlapping_pairs(collidables.into_iter())
+        .into_iter()
+        .map(|(a, b)| (a.min(b), a.max(b)))
+        .collect();
+    pairs.sort();
+    // Both snowflakes hit the ground, but not each other
+    assert_eq!(pairs, vec![(test_entity(0), test_entity(1)), (test_entity(0), test_entity(2))]);
+}
+
+#[test]
+fn test_auto_collider_bounds() {
+    let positions = [[1.0, -2.0, 0.5], [-1.0, 3.0, 0.0], [0.5, 0.0, -0.5]];
+    let bb = bounding_box_of(&positions).unwrap();
+    assert_eq!(bb.min, Vec3::new(-1.0, -2.0, -0.5));
+    assert_eq!(bb.max, Vec3::new(1.0, 3.0, 0.5));
+    assert_eq!(bounding_radius_of(&positions), Some(10f32.sqrt()));
+
+    assert!(bounding_box_of(&[]).is_none());
+    assert!(bounding_radius_of(&[]).is_none());
+}
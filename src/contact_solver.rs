@@ -0,0 +1,359 @@
+use bevy::prelude::*;
+use crate::narrowphase::ContactManifold;
+
+// Sequential impulse contact solver, after Erin Catto's Box2D.
+// Every contact point is a constraint on the relative velocity of the two
+// bodies: along the normal they may only separate, along the tangents the
+// friction impulse is limited by the normal impulse (Coulomb). The
+// constraints are solved one after the other for a few iterations, which
+// converges to the solution of the whole system. The accumulated impulses
+// are kept between frames and applied up front (warm starting), so resting
+// contacts start close to their final impulse. Penetration is removed by
+// adding a bias velocity proportional to the depth (Baumgarte).
+
+// Velocities of a body while solving. Static geometry has an inverse mass
+// and inertia of zero, so impulses don't change its velocity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SolverBody {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub angular_velocity: Vec3,
+    pub inverse_mass: f32,
+    // In world space
+    pub inverse_inertia: Mat3,
+}
+
+impl SolverBody {
+    pub fn fixed(position: Vec3) -> SolverBody {
+        SolverBody {
+            position,
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            inverse_mass: 0.0,
+            inverse_inertia: Mat3::ZERO,
+        }
+    }
+
+    fn velocity_at(&self, r: &Vec3) -> Vec3 {
+        self.velocity + self.angular_velocity.cross(*r)
+    }
+
+    // How much an impulse along direction at r resists, see effective_mass
+    fn resistance(&self, r: &Vec3, direction: &Vec3) -> f32 {
+        let r_cross_d = r.cross(*direction);
+        self.inverse_mass + (self.inverse_inertia * r_cross_d).dot(r_cross_d)
+    }
+}
+
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct SolverSettings {
+    pub iterations: usize,
+    // Fraction of the penetration that is removed per step
+    pub baumgarte: f32,
+    // Penetration that is tolerated, so resting contacts don't jitter
+    pub slop: f32,
+    // Slower impacts don't bounce, which lets bodies come to rest
+    pub restitution_threshold: f32,
+}
+
+impl Default for SolverSettings {
+    fn default() -> SolverSettings {
+        SolverSettings {
+            iterations: 10,
+            baumgarte: 0.2,
+            slop: 0.01,
+            restitution_threshold: 1.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ContactMaterial {
+    // Coulomb friction coefficient
+    pub friction: f32,
+    // 0 doesn't bounce, 1 bounces back at the speed of impact
+    pub restitution: f32,
+}
+
+impl Default for ContactMaterial {
+    fn default() -> ContactMaterial {
+        ContactMaterial {
+            friction: 0.5,
+            restitution: 0.3,
+        }
+    }
+}
+
+impl ContactMaterial {
+    // The material of a contact between two bodies, as most engines do it
+    pub fn combine(&self, other: &ContactMaterial) -> ContactMaterial {
+        ContactMaterial {
+            friction: (self.friction * other.friction).sqrt(),
+            restitution: self.restitution.max(other.restitution),
+        }
+    }
+}
+
+// Accumulated impulses of a contact point, kept for warm starting
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct CachedImpulse {
+    pub point: Vec3,
+    pub normal: f32,
+    pub tangent: [f32; 2],
+}
+
+// Contact points further apart than this are not the same between frames
+const WARM_START_DISTANCE: f32 = 0.1;
+
+#[derive(Clone, Debug)]
+struct ContactPoint {
+    point: Vec3,
+    // From the centers of the bodies to the point
+    r_a: Vec3,
+    r_b: Vec3,
+    normal_mass: f32,
+    tangent_masses: [f32; 2],
+    // Target separating velocity from restitution and position correction
+    bias: f32,
+    normal_impulse: f32,
+    tangent_impulses: [f32; 2],
+}
+
+#[derive(Clone, Debug)]
+pub struct ContactConstraint {
+    body_a: usize,
+    body_b: usize,
+    // Points from a to b
+    normal: Vec3,
+    tangents: [Vec3; 2],
+    friction: f32,
+    points: Vec<ContactPoint>,
+}
+
+impl ContactConstraint {
+    // The pair indexes into bodies, manifold.normal points from its first to its second body
+    pub fn new(
+        (body_a, body_b): (usize, usize),
+        bodies: &[SolverBody],
+        manifold: &ContactManifold,
+        material: &ContactMaterial,
+        cached: &[CachedImpulse],
+        settings: &SolverSettings,
+        dt: f32,
+    ) -> ContactConstraint {
+        let a = &bodies[body_a];
+        let b = &bodies[body_b];
+        let normal = manifold.normal;
+        let (tangent_1, tangent_2) = normal.any_orthonormal_pair();
+        let tangents = [tangent_1, tangent_2];
+        let position_bias = settings.baumgarte / dt * (manifold.depth - settings.slop).max(0.0);
+
+        let points = manifold.points.iter().map(|point| {
+            let r_a = *point - a.position;
+            let r_b = *point - b.position;
+            let approaching_velocity = (b.velocity_at(&r_b) - a.velocity_at(&r_a)).dot(normal);
+            let restitution_bias = if approaching_velocity < -settings.restitution_threshold {
+                -material.restitution * approaching_velocity
+            } else {
+                0.0
+            };
+            let warm_start = cached
+                .iter()
+                .filter(|cached| cached.point.distance(*point) < WARM_START_DISTANCE)
+                .min_by(|x, y| x.point.distance(*point).total_cmp(&y.point.distance(*point)))
+                .cloned()
+                .unwrap_or_default();
+            ContactPoint {
+                point: *point,
+                r_a,
+                r_b,
+                normal_mass: effective_mass(a, b, &r_a, &r_b, &normal),
+                tangent_masses: [
+                    effective_mass(a, b, &r_a, &r_b, &tangents[0]),
+                    effective_mass(a, b, &r_a, &r_b, &tangents[1]),
+                ],
+                bias: position_bias.max(restitution_bias),
+                normal_impulse: warm_start.normal,
+                tangent_impulses: warm_start.tangent,
+            }
+        }).collect();
+
+        ContactConstraint { body_a, body_b, normal, tangents, friction: material.friction, points }
+    }
+
+    // The accumulated impulses after solving, to warm start the next frame
+    pub fn impulses(&self) -> Vec<CachedImpulse> {
+        self.points.iter().map(|point| CachedImpulse {
+            point: point.point,
+            normal: point.normal_impulse,
+            tangent: point.tangent_impulses,
+        }).collect()
+    }
+
+    fn warm_start(&self, bodies: &mut [SolverBody]) {
+        for point in self.points.iter() {
+            let impulse = self.normal * point.normal_impulse
+                + self.tangents[0] * point.tangent_impulses[0]
+                + self.tangents[1] * point.tangent_impulses[1];
+            apply_impulse(bodies, self.body_a, self.body_b, &point.r_a, &point.r_b, &impulse);
+        }
+    }
+
+    fn solve_velocities(&mut self, bodies: &mut [SolverBody]) {
+        let (body_a, body_b) = (self.body_a, self.body_b);
+        for point in self.points.iter_mut() {
+            // Friction first, it is less important than not penetrating
+            let max_friction = self.friction * point.normal_impulse;
+            for k in 0..2 {
+                let tangent = self.tangents[k];
+                let relative_velocity = bodies[body_b].velocity_at(&point.r_b) - bodies[body_a].velocity_at(&point.r_a);
+                let lambda = -relative_velocity.dot(tangent) * point.tangent_masses[k];
+                // Clamp the accumulated impulse, not the increment, so
+                // earlier iterations can be partially undone
+                let accumulated = (point.tangent_impulses[k] + lambda).clamp(-max_friction, max_friction);
+                let change = accumulated - point.tangent_impulses[k];
+                point.tangent_impulses[k] = accumulated;
+                apply_impulse(bodies, body_a, body_b, &point.r_a, &point.r_b, &(tangent * change));
+            }
+
+            let relative_velocity = bodies[body_b].velocity_at(&point.r_b) - bodies[body_a].velocity_at(&point.r_a);
+            let lambda = (point.bias - relative_velocity.dot(self.normal)) * point.normal_mass;
+            // Contacts can only push
+            let accumulated = (point.normal_impulse + lambda).max(0.0);
+            let change = accumulated - point.normal_impulse;
+            point.normal_impulse = accumulated;
+            apply_impulse(bodies, body_a, body_b, &point.r_a, &point.r_b, &(self.normal * change));
+        }
+    }
+}
+
+// 1 / (the change in relative velocity along direction per unit impulse)
+fn effective_mass(a: &SolverBody, b: &SolverBody, r_a: &Vec3, r_b: &Vec3, direction: &Vec3) -> f32 {
+    let resistance = a.resistance(r_a, direction) + b.resistance(r_b, direction);
+    if resistance > f32::EPSILON { 1.0 / resistance } else { 0.0 }
+}
+
+// Applies impulse to b and the opposite impulse to a
+fn apply_impulse(bodies: &mut [SolverBody], a: usize, b: usize, r_a: &Vec3, r_b: &Vec3, impulse: &Vec3) {
+    let body_a = &mut bodies[a];
+    body_a.velocity -= *impulse * body_a.inverse_mass;
+    body_a.angular_velocity -= body_a.inverse_inertia * r_a.cross(*impulse);
+    let body_b = &mut bodies[b];
+    body_b.velocity += *impulse * body_b.inverse_mass;
+    body_b.angular_velocity += body_b.inverse_inertia * r_b.cross(*impulse);
+}
+
+pub fn solve(bodies: &mut [SolverBody], constraints: &mut [ContactConstraint], settings: &SolverSettings) {
+    for constraint in constraints.iter() {
+        constraint.warm_start(bodies);
+    }
+    for _ in 0..settings.iterations {
+        for constraint in constraints.iter_mut() {
+            constraint.solve_velocities(bodies);
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_material(friction: f32, restitution: f32) -> ContactMaterial {
+    ContactMaterial { friction, restitution }
+}
+
+#[cfg(test)]
+fn test_body(position: Vec3, velocity: Vec3) -> SolverBody {
+    SolverBody {
+        position,
+        velocity,
+        angular_velocity: Vec3::ZERO,
+        inverse_mass: 1.0,
+        inverse_inertia: Mat3::IDENTITY * 6.0,
+    }
+}
+
+// A unit box resting on the ground with its four bottom corners
+#[cfg(test)]
+fn test_ground_contact(position: Vec3, depth: f32) -> ContactManifold {
+    let points = [(-0.5, -0.5), (0.5, -0.5), (0.5, 0.5), (-0.5, 0.5)]
+        .iter()
+        .map(|(x, z)| position + Vec3::new(*x, -0.5, *z))
+        .collect();
+    ContactManifold { normal: Vec3::Y, depth, points }
+}
+
+#[test]
+fn test_solver_stops_falling_box() {
+    let settings = SolverSettings::default();
+    let mut bodies = vec![SolverBody::fixed(Vec3::new(0.0, -0.5, 0.0)), test_body(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.0, -0.5, 0.0))];
+    let manifold = test_ground_contact(bodies[1].position, 0.0);
+    let mut constraints = vec![ContactConstraint::new((0, 1), &bodies, &manifold, &test_material(0.5, 0.5), &[], &settings, 1.0 / 60.0)];
+    solve(&mut bodies, &mut constraints, &settings);
+    // Below the restitution threshold, so it doesn't bounce
+    assert!(bodies[1].velocity.length() < 1e-4);
+    assert!(bodies[1].angular_velocity.length() < 1e-4);
+    assert_eq!(bodies[0], SolverBody::fixed(Vec3::new(0.0, -0.5, 0.0)));
+    // Together the four points carry the momentum
+    let total: f32 = constraints[0].impulses().iter().map(|impulse| impulse.normal).sum();
+    assert!((total - 0.5).abs() < 1e-4);
+}
+
+#[test]
+fn test_solver_restitution() {
+    let settings = SolverSettings::default();
+    let mut bodies = vec![
+        test_body(Vec3::new(-1.0, 0.0, 0.0), Vec3::new(3.0, 0.0, 0.0)),
+        test_body(Vec3::new(1.0, 0.0, 0.0), Vec3::new(-3.0, 0.0, 0.0)),
+    ];
+    // Two spheres meeting head on
+    let manifold = ContactManifold { normal: Vec3::X, depth: 0.0, points: vec![Vec3::ZERO] };
+    let mut constraints = vec![ContactConstraint::new((0, 1), &bodies, &manifold, &test_material(0.5, 1.0), &[], &settings, 1.0 / 60.0)];
+    solve(&mut bodies, &mut constraints, &settings);
+    // A perfectly elastic collision of equal masses swaps the velocities
+    assert!((bodies[0].velocity - Vec3::new(-3.0, 0.0, 0.0)).length() < 1e-4);
+    assert!((bodies[1].velocity - Vec3::new(3.0, 0.0, 0.0)).length() < 1e-4);
+}
+
+#[test]
+fn test_solver_friction() {
+    let settings = SolverSettings::default();
+    let friction = 0.5;
+    let ground = SolverBody::fixed(Vec3::new(0.0, -0.5, 0.0));
+    let dt = 1.0 / 60.0;
+    // Pressed onto the ground by gravity during one step, sliding along x
+    let pressing_velocity = -9.81 * dt;
+    let mut bodies = vec![ground, test_body(Vec3::new(0.0, 0.5, 0.0), Vec3::new(2.0, pressing_velocity, 0.0))];
+    let manifold = test_ground_contact(bodies[1].position, 0.0);
+    let mut constraints = vec![ContactConstraint::new((0, 1), &bodies, &manifold, &test_material(friction, 0.0), &[], &settings, dt)];
+    solve(&mut bodies, &mut constraints, &settings);
+    // Coulomb friction takes at most friction * normal impulse
+    let expected = 2.0 + friction * pressing_velocity;
+    assert!((bodies[1].velocity.x - expected).abs() < 1e-3);
+    assert!(bodies[1].velocity.y.abs() < 1e-3);
+
+    // Slow enough to stop entirely
+    let mut bodies = vec![ground, test_body(Vec3::new(0.0, 0.5, 0.0), Vec3::new(0.01, pressing_velocity, 0.0))];
+    let mut constraints = vec![ContactConstraint::new((0, 1), &bodies, &manifold, &test_material(friction, 0.0), &[], &settings, dt)];
+    solve(&mut bodies, &mut constraints, &settings);
+    assert!(bodies[1].velocity.length() < 1e-4);
+}
+
+#[test]
+fn test_solver_warm_start_and_penetration() {
+    let settings = SolverSettings::default();
+    let dt = 1.0 / 60.0;
+    let resting = test_body(Vec3::new(0.0, 0.45, 0.0), Vec3::new(0.0, -9.81 * dt, 0.0));
+    let manifold = test_ground_contact(resting.position, 0.1);
+
+    let mut bodies = vec![SolverBody::fixed(Vec3::new(0.0, -0.5, 0.0)), resting];
+    let mut constraints = vec![ContactConstraint::new((0, 1), &bodies, &manifold, &test_material(0.5, 0.0), &[], &settings, dt)];
+    solve(&mut bodies, &mut constraints, &settings);
+    // Pushed out of the ground at baumgarte * (depth - slop) / dt
+    let expected = settings.baumgarte * (0.1 - settings.slop) / dt;
+    assert!((bodies[1].velocity.y - expected).abs() < 1e-3);
+
+    // The impulses of the last frame are applied before the first iteration
+    let cached = constraints[0].impulses();
+    let mut warm_bodies = vec![SolverBody::fixed(Vec3::new(0.0, -0.5, 0.0)), resting];
+    let warm = ContactConstraint::new((0, 1), &warm_bodies, &manifold, &test_material(0.5, 0.0), &cached, &settings, dt);
+    warm.warm_start(&mut warm_bodies);
+    assert!((warm_bodies[1].velocity.y - expected).abs() < 1e-3);
+}
@@ -0,0 +1,611 @@
+use std::collections::BinaryHeap;
+
+use bevy::prelude::*;
+use crate::bvh::{AABB, QueueEntry};
+#[cfg(test)]
+use crate::bvh_test_fixtures::*;
+
+// A BVH that can be changed incrementally, modelled after Box2D's b2DynamicTree.
+// Leaves store an enlarged ("fat") bounding box, so objects that only move a
+// little don't have to be reinserted. Insertion picks the sibling with the
+// lowest surface area cost and the tree is kept balanced with AVL-like rotations.
+// All nodes live in a Vec and are addressed by index, a leaf keeps its index
+// for its whole lifetime, which makes the index usable as a handle.
+
+// Freed slots get reused, so a handle also carries the generation of its slot.
+// Handles of removed leaves don't match anymore and are rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DynamicBVHHandle {
+    index: usize,
+    generation: u32,
+}
+
+#[derive(Debug)]
+struct DynamicNode<T> {
+    // Fat box for leaves, union of the children for inner nodes
+    bbox: AABB,
+    // The box that was handed in by the user, only meaningful for leaves
+    tight_bbox: AABB,
+    parent: Option<usize>,
+    children: Option<(usize, usize)>,
+    // Leaves have height 0
+    height: usize,
+    data: Option<T>,
+    // Counts how often the slot has been reused
+    generation: u32,
+}
+
+impl<T> DynamicNode<T> {
+    fn is_leaf(&self) -> bool {
+        self.children.is_none()
+    }
+}
+
+#[derive(Debug)]
+pub struct DynamicBVH<T> {
+    nodes: Vec<DynamicNode<T>>,
+    free_nodes: Vec<usize>,
+    root: Option<usize>,
+    // How much the leaf boxes get enlarged on every side
+    margin: f32,
+    leaf_count: usize,
+}
+
+impl<T> Default for DynamicBVH<T> {
+    fn default() -> DynamicBVH<T> {
+        DynamicBVH::new(0.1)
+    }
+}
+
+impl<T> DynamicBVH<T> {
+    pub fn new(margin: f32) -> DynamicBVH<T> {
+        DynamicBVH {
+            nodes: Vec::new(),
+            free_nodes: Vec::new(),
+            root: None,
+            margin,
+            leaf_count: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaf_count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaf_count == 0
+    }
+
+    pub fn height(&self) -> usize {
+        self.root.map_or(0, |root| self.nodes[root].height)
+    }
+
+    pub fn get(&self, handle: DynamicBVHHandle) -> Option<&T> {
+        self.leaf(handle).and_then(|leaf| self.nodes[leaf].data.as_ref())
+    }
+
+    // The index of the leaf, if the handle still refers to one
+    fn leaf(&self, handle: DynamicBVHHandle) -> Option<usize> {
+        let node = self.nodes.get(handle.index)?;
+        if node.generation != handle.generation || node.data.is_none() {
+            return None;
+        }
+        Some(handle.index)
+    }
+
+    // The (fat) boxes of all nodes, grouped by depth. Index 0 holds the root
+    pub fn boxes_by_depth(&self) -> Vec<Vec<AABB>> {
+        let mut levels = Vec::new();
+        let mut current_level: Vec<usize> = self.root.into_iter().collect();
+        while !current_level.is_empty() {
+            levels.push(current_level.iter().map(|index| self.nodes[*index].bbox).collect());
+            current_level = current_level
+                .iter()
+                .filter_map(|index| self.nodes[*index].children)
+                .flat_map(|(left, right)| [left, right])
+                .collect();
+        }
+        levels
+    }
+
+    pub fn insert(&mut self, data: T, bbox: AABB) -> DynamicBVHHandle {
+        let leaf = self.allocate_node(DynamicNode {
+            bbox: bbox.expanded(self.margin),
+            tight_bbox: bbox,
+            parent: None,
+            children: None,
+            height: 0,
+            data: Some(data),
+            generation: 0,
+        });
+        self.insert_leaf(leaf);
+        self.leaf_count += 1;
+        DynamicBVHHandle {
+            index: leaf,
+            generation: self.nodes[leaf].generation,
+        }
+    }
+
+    // Returns None if the handle is stale
+    pub fn remove(&mut self, handle: DynamicBVHHandle) -> Option<T> {
+        let leaf = self.leaf(handle)?;
+        self.remove_leaf(leaf);
+        self.leaf_count -= 1;
+        let data = self.nodes[leaf].data.take();
+        self.free_nodes.push(leaf);
+        data
+    }
+
+    // Returns Some(true) if the leaf had to be reinserted, Some(false) if the
+    // new box still fits into the fat box and None if the handle is stale
+    pub fn update(&mut self, handle: DynamicBVHHandle, bbox: AABB) -> Option<bool> {
+        let leaf = self.leaf(handle)?;
+        self.nodes[leaf].tight_bbox = bbox;
+        if self.nodes[leaf].bbox.contains_aabb(&bbox) {
+            return Some(false);
+        }
+        self.remove_leaf(leaf);
+        self.nodes[leaf].bbox = bbox.expanded(self.margin);
+        self.insert_leaf(leaf);
+        Some(true)
+    }
+
+    fn allocate_node(&mut self, node: DynamicNode<T>) -> usize {
+        if let Some(index) = self.free_nodes.pop() {
+            let generation = self.nodes[index].generation.wrapping_add(1);
+            self.nodes[index] = DynamicNode { generation, ..node };
+            index
+        } else {
+            self.nodes.push(node);
+            self.nodes.len() - 1
+        }
+    }
+
+    fn free_node(&mut self, index: usize) {
+        self.nodes[index].parent = None;
+        self.nodes[index].children = None;
+        self.free_nodes.push(index);
+    }
+
+    fn replace_child(&mut self, parent: usize, old_child: usize, new_child: usize) {
+        let (left, right) = self.nodes[parent].children.unwrap();
+        if left == old_child {
+            self.nodes[parent].children = Some((new_child, right));
+        } else {
+            assert_eq!(right, old_child);
+            self.nodes[parent].children = Some((left, new_child));
+        }
+    }
+
+    fn insert_leaf(&mut self, leaf: usize) {
+        let root = match self.root {
+            Some(root) => root,
+            None => {
+                self.root = Some(leaf);
+                self.nodes[leaf].parent = None;
+                return;
+            }
+        };
+
+        // Walk down to the sibling that increases the surface area the least
+        let leaf_box = self.nodes[leaf].bbox;
+        let mut index = root;
+        while let Some((left, right)) = self.nodes[index].children {
+            let area = self.nodes[index].bbox.surface_area();
+            let combined_area = AABB::outer(&self.nodes[index].bbox, &leaf_box).surface_area();
+            // Cost of creating a new parent for this node and the new leaf
+            let cost = 2.0 * combined_area;
+            // Minimum cost of pushing the leaf further down the tree
+            let inheritance_cost = 2.0 * (combined_area - area);
+            let child_cost = |child: usize| {
+                let child_node = &self.nodes[child];
+                let combined = AABB::outer(&child_node.bbox, &leaf_box).surface_area();
+                if child_node.is_leaf() {
+                    combined + inheritance_cost
+                } else {
+                    combined - child_node.bbox.surface_area() + inheritance_cost
+                }
+            };
+            let left_cost = child_cost(left);
+            let right_cost = child_cost(right);
+            if cost < left_cost && cost < right_cost {
+                break;
+            }
+            index = if left_cost < right_cost { left } else { right };
+        }
+        let sibling = index;
+
+        // Create a new parent for the sibling and the leaf
+        let old_parent = self.nodes[sibling].parent;
+        let new_parent = self.allocate_node(DynamicNode {
+            bbox: AABB::outer(&leaf_box, &self.nodes[sibling].bbox),
+            tight_bbox: AABB::default(),
+            parent: old_parent,
+            children: Some((sibling, leaf)),
+            height: self.nodes[sibling].height + 1,
+            data: None,
+            generation: 0,
+        });
+        match old_parent {
+            Some(old_parent) => self.replace_child(old_parent, sibling, new_parent),
+            None => self.root = Some(new_parent),
+        }
+        self.nodes[sibling].parent = Some(new_parent);
+        self.nodes[leaf].parent = Some(new_parent);
+
+        self.refit_ancestors(Some(new_parent));
+    }
+
+    fn remove_leaf(&mut self, leaf: usize) {
+        if self.root == Some(leaf) {
+            self.root = None;
+            return;
+        }
+        let parent = self.nodes[leaf].parent.unwrap();
+        let grand_parent = self.nodes[parent].parent;
+        let (left, right) = self.nodes[parent].children.unwrap();
+        let sibling = if left == leaf { right } else { left };
+
+        // The sibling takes the place of the parent
+        self.nodes[sibling].parent = grand_parent;
+        match grand_parent {
+            Some(grand_parent) => {
+                self.replace_child(grand_parent, parent, sibling);
+                self.refit_ancestors(Some(grand_parent));
+            }
+            None => self.root = Some(sibling),
+        }
+        self.free_node(parent);
+        self.nodes[leaf].parent = None;
+    }
+
+    // Rebalance and recompute boxes and heights from index up to the root
+    fn refit_ancestors(&mut self, mut index: Option<usize>) {
+        while let Some(i) = index {
+            let i = self.balance(i);
+            let (left, right) = self.nodes[i].children.unwrap();
+            self.nodes[i].height = 1 + self.nodes[left].height.max(self.nodes[right].height);
+            self.nodes[i].bbox = AABB::outer(&self.nodes[left].bbox, &self.nodes[right].bbox);
+            index = self.nodes[i].parent;
+        }
+    }
+
+    // If one subtree of a is more than one level higher than the other, rotate
+    // it up. Returns the index of the node that now takes the place of a
+    fn balance(&mut self, a: usize) -> usize {
+        if self.nodes[a].height < 2 {
+            return a;
+        }
+        let (b, c) = self.nodes[a].children.unwrap();
+        let balance = self.nodes[c].height as isize - self.nodes[b].height as isize;
+        if balance > 1 {
+            return self.rotate_up(a, c, b);
+        }
+        if balance < -1 {
+            return self.rotate_up(a, b, c);
+        }
+        a
+    }
+
+    // Rotates the higher child up into the place of a. Its higher grandchild
+    // stays with it, the lower one moves over to a.
+    // In an AVL tree the order of the children matters, so when the inner
+    // grandchild is the higher one it takes a double rotation. The children of
+    // a BVH have no order, picking which grandchild moves over covers that
+    // case: with heights lower = h and higher = h + 2, a ends up with h + 1 or
+    // h + 2 next to keep with h + 1, so both a and higher are balanced again
+    fn rotate_up(&mut self, a: usize, higher: usize, lower: usize) -> usize {
+        let (f, g) = self.nodes[higher].children.unwrap();
+
+        // higher takes the place of a
+        let a_parent = self.nodes[a].parent;
+        self.nodes[higher].parent = a_parent;
+        match a_parent {
+            Some(a_parent) => self.replace_child(a_parent, a, higher),
+            None => self.root = Some(higher),
+        }
+        self.nodes[a].parent = Some(higher);
+
+        let (keep, move_over) = if self.nodes[f].height > self.nodes[g].height {
+            (f, g)
+        } else {
+            (g, f)
+        };
+        self.nodes[higher].children = Some((a, keep));
+        self.replace_child(a, higher, move_over);
+        self.nodes[move_over].parent = Some(a);
+
+        self.nodes[a].bbox = AABB::outer(&self.nodes[lower].bbox, &self.nodes[move_over].bbox);
+        self.nodes[a].height = 1 + self.nodes[lower].height.max(self.nodes[move_over].height);
+        self.nodes[higher].bbox = AABB::outer(&self.nodes[a].bbox, &self.nodes[keep].bbox);
+        self.nodes[higher].height = 1 + self.nodes[a].height.max(self.nodes[keep].height);
+        higher
+    }
+}
+
+impl<T: Clone> DynamicBVH<T> {
+    pub fn get_in_radius(&self, position: &Vec3, radius: f32) -> Vec<T> {
+        let mut in_radius = Vec::new();
+        let mut stack: Vec<usize> = self.root.into_iter().collect();
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if node.bbox.distance(position) > radius {
+                continue;
+            }
+            match node.children {
+                Some((left, right)) => {
+                    stack.push(left);
+                    stack.push(right);
+                }
+                None => {
+                    if node.tight_bbox.distance(position) <= radius {
+                        in_radius.push(node.data.as_ref().unwrap().clone());
+                    }
+                }
+            }
+        }
+        in_radius
+    }
+
+    // Same best-first traversal as BVH::get_n_closest. Leaves are queued
+    // with the distance to their tight box, which is never smaller than the
+    // distance to the fat box, so the queue order stays valid
+    pub fn get_n_closest(&self, position: &Vec3, n: usize) -> Vec<(T, f32)> {
+        let mut closest = Vec::with_capacity(n);
+        if n == 0 {
+            return closest;
+        }
+        let mut queue = BinaryHeap::new();
+        if let Some(root) = self.root {
+            queue.push(QueueEntry { distance: self.queue_distance(root, position), node: root });
+        }
+        while let Some(QueueEntry { distance, node: index }) = queue.pop() {
+            match self.nodes[index].children {
+                Some((left, right)) => {
+                    queue.push(QueueEntry { distance: self.queue_distance(left, position), node: left });
+                    queue.push(QueueEntry { distance: self.queue_distance(right, position), node: right });
+                }
+                None => {
+                    closest.push((self.nodes[index].data.as_ref().unwrap().clone(), distance));
+                    if closest.len() == n {
+                        break;
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    // See BVH::raycast. Leaves are tested with their tight box
+    pub fn raycast(&self, origin: &Vec3, direction: &Vec3) -> Option<(T, f32)> {
+        let mut closest_hit: Option<(usize, f32)> = None;
+        let mut stack: Vec<(usize, f32)> = self.root
+            .and_then(|root| self.nodes[root].bbox.ray_intersect(origin, direction).map(|t| (root, t)))
+            .into_iter()
+            .collect();
+        while let Some((index, t)) = stack.pop() {
+            if closest_hit.map_or(false, |hit| t >= hit.1) {
+                continue;
+            }
+            let node = &self.nodes[index];
+            let (left, right) = match node.children {
+                Some(children) => children,
+                None => {
+                    if let Some(t) = node.tight_bbox.ray_intersect(origin, direction) {
+                        if closest_hit.map_or(true, |hit| t < hit.1) {
+                            closest_hit = Some((index, t));
+                        }
+                    }
+                    continue;
+                }
+            };
+            // Push the farther child first, so the nearer one is visited first
+            let mut child_hits: Vec<(usize, f32)> = [left, right]
+                .into_iter()
+                .filter_map(|child| self.nodes[child].bbox.ray_intersect(origin, direction).map(|t| (child, t)))
+                .collect();
+            child_hits.sort_by(|a, b| b.1.total_cmp(&a.1));
+            stack.extend(child_hits);
+        }
+        closest_hit.map(|(index, t)| (self.nodes[index].data.as_ref().unwrap().clone(), t))
+    }
+
+    fn queue_distance(&self, index: usize, position: &Vec3) -> f32 {
+        let node = &self.nodes[index];
+        if node.is_leaf() {
+            node.tight_bbox.distance(position)
+        } else {
+            node.bbox.distance(position)
+        }
+    }
+}
+
+// Checks parent links, heights, balance and that every box encloses its children
+#[cfg(test)]
+fn test_check_invariants<T>(tree: &DynamicBVH<T>) {
+    fn check<T>(tree: &DynamicBVH<T>, index: usize) -> usize {
+        let node = &tree.nodes[index];
+        match node.children {
+            None => {
+                assert!(node.data.is_some());
+                assert!(node.bbox.contains_aabb(&node.tight_bbox));
+                assert_eq!(node.height, 0);
+                1
+            }
+            Some((left, right)) => {
+                assert!(node.data.is_none());
+                assert_eq!(tree.nodes[left].parent, Some(index));
+                assert_eq!(tree.nodes[right].parent, Some(index));
+                assert!(node.bbox.contains_aabb(&tree.nodes[left].bbox));
+                assert!(node.bbox.contains_aabb(&tree.nodes[right].bbox));
+                let left_height = tree.nodes[left].height;
+                let right_height = tree.nodes[right].height;
+                assert_eq!(node.height, 1 + left_height.max(right_height));
+                assert!((left_height as isize - right_height as isize).abs() <= 1);
+                check(tree, left) + check(tree, right)
+            }
+        }
+    }
+    match tree.root {
+        Some(root) => {
+            assert!(tree.nodes[root].parent.is_none());
+            assert_eq!(check(tree, root), tree.len());
+        }
+        None => assert!(tree.is_empty()),
+    }
+}
+
+#[cfg(test)]
+fn test_box_at(position: Vec3) -> AABB {
+    AABB::new(position, position + Vec3::splat(0.5))
+}
+
+#[test]
+fn test_dynamic_bvh_insert_is_balanced() {
+    let mut tree = DynamicBVH::new(0.1);
+    // Sorted input is the worst case for a naive insertion
+    for i in 0..128 {
+        tree.insert(i, test_box_at(Vec3::splat(i as f32)));
+        test_check_invariants(&tree);
+    }
+    assert_eq!(tree.len(), 128);
+    // An AVL tree with 128 leaves is at most ~1.44 log2(n) high
+    assert!(tree.height() <= 10);
+}
+
+#[test]
+fn test_dynamic_bvh_remove() {
+    let mut tree = DynamicBVH::new(0.1);
+    let handles: Vec<DynamicBVHHandle> = (0..32)
+        .map(|i| tree.insert(i, test_box_at(Vec3::new(i as f32, 0.0, 0.0))))
+        .collect();
+    for handle in handles.iter().step_by(2) {
+        assert!(tree.remove(*handle).is_some());
+        test_check_invariants(&tree);
+    }
+    // removing twice does nothing
+    assert!(tree.remove(handles[0]).is_none());
+    assert_eq!(tree.len(), 16);
+    assert_eq!(tree.get(handles[1]), Some(&1));
+    assert_eq!(tree.get(handles[0]), None);
+    for handle in handles.iter().skip(1).step_by(2) {
+        tree.remove(*handle);
+        test_check_invariants(&tree);
+    }
+    assert!(tree.is_empty());
+    assert_eq!(tree.height(), 0);
+}
+
+#[test]
+fn test_dynamic_bvh_stale_handles() {
+    let mut tree = DynamicBVH::new(0.1);
+    let first = tree.insert(0, test_box_at(Vec3::ZERO));
+    let other = tree.insert(1, test_box_at(Vec3::X));
+    assert_eq!(tree.remove(first), Some(0));
+    // Reuses the slots that were freed by the removal
+    let second = tree.insert(2, test_box_at(Vec3::Y));
+    let third = tree.insert(3, test_box_at(Vec3::Z));
+    assert!([second.index, third.index].contains(&first.index));
+
+    assert_eq!(tree.get(first), None);
+    assert_eq!(tree.update(first, test_box_at(Vec3::splat(10.0))), None);
+    assert_eq!(tree.remove(first), None);
+    test_check_invariants(&tree);
+    assert_eq!(tree.len(), 3);
+    assert_eq!(tree.get(other), Some(&1));
+    assert_eq!(tree.get(second), Some(&2));
+    assert_eq!(tree.get(third), Some(&3));
+}
+
+#[test]
+fn test_dynamic_bvh_stays_balanced() {
+    // Inserts, moves and removes in a scrambled order, the heights of
+    // siblings never differ by more than one
+    let mut tree = DynamicBVH::new(0.1);
+    let mut handles = Vec::new();
+    let mut seed = 12345u32;
+    let mut random = move || {
+        seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+        (seed >> 8) as f32 / (1 << 24) as f32
+    };
+    for i in 0..2000 {
+        let position = Vec3::new(random(), random(), random()) * 50.0;
+        let action = random();
+        if handles.is_empty() || action < 0.5 {
+            handles.push(tree.insert(i, test_box_at(position)));
+        } else if action < 0.8 {
+            let handle = handles[(random() * handles.len() as f32) as usize % handles.len()];
+            assert!(tree.update(handle, test_box_at(position)).is_some());
+        } else {
+            let handle = handles.swap_remove((random() * handles.len() as f32) as usize % handles.len());
+            assert!(tree.remove(handle).is_some());
+        }
+        test_check_invariants(&tree);
+    }
+    assert_eq!(tree.len(), handles.len());
+}
+
+#[test]
+fn test_dynamic_bvh_update() {
+    let mut tree = DynamicBVH::new(0.25);
+    let handles: Vec<DynamicBVHHandle> = (0..16)
+        .map(|i| tree.insert(i, test_box_at(Vec3::new(i as f32, 0.0, 0.0))))
+        .collect();
+    // small moves stay within the fat box
+    assert_eq!(tree.update(handles[3], test_box_at(Vec3::new(3.1, 0.0, 0.0))), Some(false));
+    // large moves don't
+    assert_eq!(tree.update(handles[3], test_box_at(Vec3::new(20.0, 0.0, 0.0))), Some(true));
+    test_check_invariants(&tree);
+    // handles stay valid after reinsertion
+    assert_eq!(tree.get(handles[3]), Some(&3));
+    let closest = tree.get_n_closest(&Vec3::new(21.0, 0.0, 0.0), 1);
+    assert_eq!(closest[0].0, 3);
+}
+
+#[test]
+fn test_dynamic_bvh_queries_match_brute_force() {
+    let mut tree = DynamicBVH::new(0.1);
+    let mut boxes = scattered_boxes(64, [64, 17, 13], 1.0, 0.5);
+    let handles: Vec<DynamicBVHHandle> = boxes.iter()
+        .map(|(data, bbox)| tree.insert(*data, *bbox))
+        .collect();
+    // move everything around a few times
+    for step in 1..4 {
+        for (i, (_, bbox)) in boxes.iter_mut().enumerate() {
+            let offset = Vec3::new(((i * step * 7) % 5) as f32, ((i * step) % 3) as f32, step as f32);
+            *bbox = bbox.translated(&offset);
+            tree.update(handles[i], *bbox);
+        }
+        test_check_invariants(&tree);
+    }
+
+    let position = Vec3::new(30.0, 4.0, 9.0);
+    let closest: Vec<f32> = tree.get_n_closest(&position, 10).iter().map(|c| c.1).collect();
+    assert_eq!(closest, brute_force_n_closest(&boxes, &position, 10));
+
+    // along x, through one of the boxes
+    let origin = boxes[10].1.center() - Vec3::new(100.0, 0.0, 0.0);
+    let direction = Vec3::X;
+    let expected = brute_force_raycast(&boxes, &origin, &direction);
+    assert!(expected.is_some());
+    assert_eq!(tree.raycast(&origin, &direction), expected);
+    assert!(tree.raycast(&origin, &-direction).is_none());
+
+    let mut in_radius = tree.get_in_radius(&position, 5.0);
+    in_radius.sort();
+    assert_eq!(in_radius, brute_force_in_radius(&boxes, &position, 5.0));
+}
+
+#[test]
+fn test_dynamic_bvh_boxes_by_depth() {
+    let mut tree = DynamicBVH::new(0.1);
+    assert!(tree.boxes_by_depth().is_empty());
+    for i in 0..16 {
+        tree.insert(i, test_box_at(Vec3::new(i as f32, 0.0, 0.0)));
+    }
+    let levels = tree.boxes_by_depth();
+    assert_eq!(levels.len(), tree.height() + 1);
+    assert_eq!(levels[0].len(), 1);
+    // A binary tree with 16 leaves has 15 inner nodes
+    assert_eq!(levels.iter().map(|level| level.len()).sum::<usize>(), 31);
+}
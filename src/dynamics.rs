@@ -1,34 +1,74 @@
 use bevy::prelude::*;
+use std::collections::HashMap;
+use crate::bvh::AABB;
+use crate::collision_detection::{Collidable, CollisionDetectionSystem, Contacts, Sensor};
+use crate::contact_solver::{solve, CachedImpulse, ContactConstraint, ContactMaterial, SolverBody, SolverSettings};
+use crate::inertia::{box_inertia, world_inverse_inertia};
+use crate::integrator::{integrate_rotation, step_angular_velocity, Integrator, LinearState};
+use crate::narrowphase::Shape;
+use crate::physics_schedule::{PhysicsSchedule, PhysicsSchedulePlugin, PhysicsStage, PhysicsSystem};
 
 pub struct DynamicsPlugin;
 impl Plugin for DynamicsPlugin {
     fn build(&self, app: &mut App) {
+        if !app.is_plugin_added::<PhysicsSchedulePlugin>() {
+            app.add_plugin(PhysicsSchedulePlugin);
+        }
         app.init_resource::<Gravity>()
+            // Filled by the CollisionDetectionPlugin, without it nothing collides
+            .init_resource::<Contacts>()
+            .init_resource::<SolverSettings>()
+            .init_resource::<ContactImpulseCache>()
+            .init_resource::<Integrator>()
             .add_startup_system(spawn_test_box)
-            .add_system(gravity)
-            .add_system(dynamic_simulation);
+            .add_system_to_stage(PhysicsStage, integrate_velocities.after(PhysicsSystem::Begin))
+            // Uses the contacts of the positions before this step
+            .add_system_to_stage(
+                PhysicsStage,
+                solve_contacts
+                    .after(integrate_velocities)
+                    .after(CollisionDetectionSystem::Narrowphase)
+            )
+            .add_system_to_stage(
+                PhysicsStage,
+                integrate_positions
+                    .after(solve_contacts)
+                    .before(PhysicsSystem::End)
+            )
+            // Forces applied during Update act on every substep of the frame
+            .add_system_to_stage(CoreStage::PostUpdate, clear_forces);
     }
 }
 
 #[derive(Component)]
 pub struct RigidBody {
     mass: f32,
+    // Both in body space, see world_inverted_inertia
+    inertia: Mat3,
     inverted_inertia: Mat3,
     velocity: Vec3,
     angular_velocity: Vec3,
     force: Vec3,
     torque: Vec3,
+    material: ContactMaterial,
+    // What the integrator moves the body in addition to velocity * dt.
+    // The contact solver only changes the velocity, so higher order
+    // integrators keep their accuracy without bypassing it
+    displacement_correction: Vec3,
 }
 
 impl Default for RigidBody {
     fn default() -> RigidBody {
         RigidBody {
             mass: 1.0,
-            inverted_inertia: Mat3::identity(),
-            velocity: Vec3::new(0.0,0.0,0.0),
-            angular_velocity: Vec3::new(0.0,0.0,0.0),
-            force: Vec3::new(0.0,0.0,0.0),
-            torque: Vec3::new(0.0,0.0,0.0),
+            inertia: Mat3::IDENTITY,
+            inverted_inertia: Mat3::IDENTITY,
+            velocity: Vec3::ZERO,
+            angular_velocity: Vec3::ZERO,
+            force: Vec3::ZERO,
+            torque: Vec3::ZERO,
+            material: ContactMaterial::default(),
+            displacement_correction: Vec3::ZERO,
         }
     }
 }
@@ -45,14 +85,37 @@ impl RigidBody {
         let inverted_inertia = inertia.inverse();
         RigidBody {
                 mass,
+                inertia,
                 inverted_inertia,
                 velocity,
                 angular_velocity,
                 force,
                 torque,
+                material: ContactMaterial::default(),
+                displacement_correction: Vec3::ZERO,
+        }
+    }
+
+    // inertia is in body space, e.g. from the helpers in crate::inertia
+    pub fn with_inertia(self, inertia: Mat3) -> RigidBody {
+        RigidBody {
+            inertia,
+            inverted_inertia: inertia.inverse(),
+            ..self
+        }
+    }
+
+    pub fn with_material(self, material: ContactMaterial) -> RigidBody {
+        RigidBody {
+            material,
+            ..self
         }
     }
 
+    pub fn world_inverted_inertia(&self, rotation: &Quat) -> Mat3 {
+        world_inverse_inertia(&self.inverted_inertia, rotation)
+    }
+
     pub fn apply_force(&mut self, force: Vec3) {
         self.force += force;
     }
@@ -62,35 +125,197 @@ impl RigidBody {
     }
 }
 
-fn dynamic_simulation(
-    time: Res<Time>,
-    mut query: Query<(&mut RigidBody, &mut Transform)>,
+// Continuous collision detection for fast bodies. Without it a body that
+// moves further than the thickness of an obstacle within one step can pass
+// through it. The motion of bodies with Ccd and a Collidable is clamped to
+// the first contact with static geometry (Collidables without a RigidBody)
+#[derive(Component, Clone, Copy, Debug, Default)]
+pub struct Ccd;
+
+// The fraction of displacement until the body hits the first obstacle,
+// and the normal of the face it hits. The obstacles with their world boxes
+fn time_of_impact(
+    bbox: &AABB,
+    displacement: &Vec3,
+    collidable: &Collidable,
+    obstacles: &[(&Collidable, AABB)],
+) -> Option<(f32, Vec3)> {
+    obstacles.iter()
+        .filter(|(obstacle, _)| collidable.can_collide(obstacle))
+        .filter_map(|(_, obstacle_bbox)| bbox.sweep(displacement, obstacle_bbox))
+        .min_by(|a, b| a.0.total_cmp(&b.0))
+}
+
+// How often the rest of a motion is swept again after hitting an obstacle,
+// e.g. sliding along a floor into a wall
+const CCD_ITERATIONS: usize = 3;
+
+// How far the body gets of displacement. It stops at the first obstacle,
+// the velocity into it is dropped and the rest of the motion slides along
+// the face it hit. A body resting on a floor keeps hitting it at 0, so
+// gravity never pulls it through
+fn sweep_motion(
+    bbox: &AABB,
+    displacement: Vec3,
+    velocity: &mut Vec3,
+    collidable: &Collidable,
+    obstacles: &[(&Collidable, AABB)],
+) -> Vec3 {
+    let mut moved = Vec3::ZERO;
+    let mut remaining = displacement;
+    for _ in 0..CCD_ITERATIONS {
+        match time_of_impact(&bbox.translated(&moved), &remaining, collidable, obstacles) {
+            None => return moved + remaining,
+            Some((toi, normal)) => {
+                moved += remaining * toi;
+                remaining *= 1.0 - toi;
+                remaining -= normal * remaining.dot(normal).min(0.0);
+                let into_obstacle = velocity.dot(normal);
+                if into_obstacle < 0.0 {
+                    *velocity -= normal * into_obstacle;
+                }
+            }
+        }
+    }
+    moved
+}
+
+// Gravity is part of the force sum, so it is applied in the same place
+// as every other force. All of them are constant over a substep, so
+// VelocityVerlet and RungeKutta4 are both exact here and only differ from
+// SemiImplicitEuler by half a step of acceleration in the position. The
+// higher order integrators pay off for callers of Integrator::step whose
+// acceleration depends on the position or velocity
+fn integrate_velocities(
+    schedule: Res<PhysicsSchedule>,
+    integrator: Res<Integrator>,
+    gravity: Res<Gravity>,
+    mut query: Query<(&mut RigidBody, &Transform)>,
 ) {
-    let dt = time.delta_seconds();
-    // Update velocities
-    for (mut rigid_body, _transform) in query.iter_mut() {
-        let acc = rigid_body.force / rigid_body.mass;
-        rigid_body.velocity += acc * dt;
-        // reset force
-        rigid_body.force = Vec3::zero();
-        
-        // I think if I use an inertia tensor, I have to invert it here
-        // '/' is elementwise division
-        let ang_acc = rigid_body.inverted_inertia * rigid_body.torque;
-        rigid_body.angular_velocity += ang_acc * dt;
-        // reset force
-        rigid_body.torque = Vec3::zero();
+    let dt = schedule.delta_seconds();
+    for (mut rigid_body, transform) in query.iter_mut() {
+        let acc = rigid_body.force / rigid_body.mass + gravity.acceleration;
+        let state = LinearState {
+            position: transform.translation,
+            velocity: rigid_body.velocity,
+        };
+        let next = integrator.step(&state, dt, |_, _| acc);
+        rigid_body.displacement_correction = next.position - state.position - next.velocity * dt;
+        rigid_body.velocity = next.velocity;
+
+        rigid_body.angular_velocity = step_angular_velocity(
+            &rigid_body.inertia,
+            &rigid_body.inverted_inertia,
+            &transform.rotation,
+            rigid_body.angular_velocity,
+            rigid_body.torque,
+            dt,
+        );
     } 
-    // Update positions
-    for (rigid_body, mut transform) in query.iter_mut() {
-        transform.translation += rigid_body.velocity * dt;
-        let angle = rigid_body.angular_velocity.length() * dt;
-        if angle != 0.0 {
-            let axis = rigid_body.angular_velocity.clone().normalize();
-            // Is rotation with (0,0,0),0 neutral? -> Yes
-            transform.rotate(Quat::from_axis_angle(axis, angle));
+}
+
+fn clear_forces(mut query: Query<&mut RigidBody>) {
+    for mut rigid_body in query.iter_mut() {
+        rigid_body.force = Vec3::ZERO;
+        rigid_body.torque = Vec3::ZERO;
+    }
+}
+
+// Accumulated impulses of the previous substep's contacts, keyed like Contacts
+#[derive(Resource, Default)]
+struct ContactImpulseCache(HashMap<(Entity, Entity), Vec<CachedImpulse>>);
+
+// Turns the velocities into ones that don't make the bodies penetrate.
+// Collidables without a RigidBody are static and can't be pushed
+fn solve_contacts(
+    schedule: Res<PhysicsSchedule>,
+    settings: Res<SolverSettings>,
+    contacts: Res<Contacts>,
+    mut cache: ResMut<ContactImpulseCache>,
+    mut rigid_bodies: Query<(&mut RigidBody, &Transform)>,
+    static_bodies: Query<&Transform, Without<RigidBody>>,
+) {
+    let dt = schedule.delta_seconds();
+    if dt == 0.0 {
+        return;
+    }
+    let mut entities: Vec<Entity> = Vec::new();
+    let mut bodies: Vec<SolverBody> = Vec::new();
+    let mut indices: HashMap<Entity, usize> = HashMap::new();
+    let mut materials: Vec<Option<ContactMaterial>> = Vec::new();
+    let mut index_of = |entity: Entity| -> Option<usize> {
+        if let Some(index) = indices.get(&entity) {
+            return Some(*index);
+        }
+        let (body, material) = if let Ok((rigid_body, transform)) = rigid_bodies.get(entity) {
+            let body = SolverBody {
+                position: transform.translation,
+                velocity: rigid_body.velocity,
+                angular_velocity: rigid_body.angular_velocity,
+                inverse_mass: 1.0 / rigid_body.mass,
+                inverse_inertia: rigid_body.world_inverted_inertia(&transform.rotation),
+            };
+            (body, Some(rigid_body.material))
+        } else {
+            (SolverBody::fixed(static_bodies.get(entity).ok()?.translation), None)
+        };
+        indices.insert(entity, bodies.len());
+        entities.push(entity);
+        bodies.push(body);
+        materials.push(material);
+        Some(bodies.len() - 1)
+    };
+
+    let mut indexed_contacts = Vec::new();
+    for ((a, b), manifold) in contacts.iter() {
+        if let (Some(index_a), Some(index_b)) = (index_of(*a), index_of(*b)) {
+            indexed_contacts.push(((*a, *b), (index_a, index_b), manifold));
+        }
+    }
+
+    let mut constraints = Vec::new();
+    let mut pairs = Vec::new();
+    for (pair, (index_a, index_b), manifold) in indexed_contacts {
+        let material = match (materials[index_a], materials[index_b]) {
+            (Some(material_a), Some(material_b)) => material_a.combine(&material_b),
+            (Some(material), None) | (None, Some(material)) => material,
+            // Two static bodies
+            (None, None) => continue,
+        };
+        let cached = cache.0.get(&pair).map_or(&[][..], |impulses| impulses.as_slice());
+        constraints.push(ContactConstraint::new((index_a, index_b), &bodies, manifold, &material, cached, &settings, dt));
+        pairs.push(pair);
+    }
+
+    solve(&mut bodies, &mut constraints, &settings);
+
+    for (entity, body) in entities.iter().zip(bodies.iter()) {
+        if let Ok((mut rigid_body, _)) = rigid_bodies.get_mut(*entity) {
+            rigid_body.velocity = body.velocity;
+            rigid_body.angular_velocity = body.angular_velocity;
         }
     }
+    cache.0 = pairs.into_iter().zip(constraints.iter().map(|constraint| constraint.impulses())).collect();
+}
+
+fn integrate_positions(
+    schedule: Res<PhysicsSchedule>,
+    mut query: Query<(&mut RigidBody, &mut Transform, Option<&Collidable>, Option<&Ccd>)>,
+    obstacles: Query<(&Collidable, &Transform), (Without<RigidBody>, Without<Sensor>)>,
+) {
+    let dt = schedule.delta_seconds();
+    let obstacles: Vec<(&Collidable, AABB)> = obstacles.iter()
+        .map(|(obstacle, transform)| (obstacle, obstacle.world_aabb(transform)))
+        .collect();
+    for (mut rigid_body, mut transform, collidable, ccd) in query.iter_mut() {
+        let mut displacement = rigid_body.velocity * dt + rigid_body.displacement_correction;
+        if let (Some(collidable), Some(_)) = (collidable, ccd) {
+            let bbox = collidable.world_aabb(&transform);
+            displacement = sweep_motion(&bbox, displacement, &mut rigid_body.velocity, collidable, &obstacles);
+        }
+        transform.translation += displacement;
+        transform.rotation = integrate_rotation(&transform.rotation, rigid_body.angular_velocity, dt);
+    }
 }
 
 #[derive(Resource)]
@@ -101,23 +326,11 @@ pub struct Gravity{
 impl Default for Gravity {
     fn default() -> Gravity {
         Gravity {
-            acceleration: Vec3::unit_y() * -9.81,
+            acceleration: Vec3::Y * -9.81,
         }
     }
 }
 
-fn gravity(
-    time: Res<Time>,
-    gravity: Res<Gravity>,
-    mut query: Query<&mut RigidBody>,
-) {
-    let dt = time.delta_seconds();
-    // Update velocities
-    for mut rigid_body in query.iter_mut() {
-        rigid_body.velocity += gravity.acceleration * dt;
-    }
-} 
-
 fn spawn_test_box(
     mut commands:  Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -125,12 +338,54 @@ fn spawn_test_box(
 ) {
     let mesh_handle = meshes.add(Mesh::from(shape::Box::new(1.0, 1.0, 1.0)));
     let material_handle = materials.add(Color::BLUE.into());
+    // A small stack that should come to rest on the ground
+    for i in 0..3 {
+        commands
+        .spawn(PbrBundle {
+            mesh: mesh_handle.clone(),
+            material: material_handle.clone(),
+            transform: Transform::from_translation(Vec3::new(4.0, 1.0 + i as f32 * 1.5, 4.0)),
+            ..Default::default()
+        })
+        .insert(RigidBody::default().with_inertia(box_inertia(1.0, Vec3::splat(0.5))))
+        .insert(Collidable::from_shape(Shape::Box { half_extents: Vec3::splat(0.5) }));
+    }
+
+    // Static, it has no RigidBody
+    let ground_size = Vec3::new(8.0, 0.5, 8.0);
     commands
     .spawn(PbrBundle {
-        mesh: mesh_handle,
-        material: material_handle,
-        transform: Transform::from_translation(Vec3::new(4.0, 4.0, 4.0)),
+        mesh: meshes.add(Mesh::from(shape::Box::new(ground_size.x, ground_size.y, ground_size.z))),
+        material: materials.add(Color::rgb(1.0, 0.9, 0.9).into()),
+        transform: Transform::from_translation(Vec3::new(4.0, -0.25, 4.0)),
         ..Default::default()
     })
-    .insert(RigidBody::default());
-}
\ No newline at end of file
+    .insert(Collidable::from_shape(Shape::Box { half_extents: ground_size / 2.0 }));
+}
+
+#[test]
+fn test_ccd_keeps_body_on_thin_floor() {
+    use crate::bvh::SWEEP_TOLERANCE;
+    use crate::collision_detection::BoundingBox;
+    let floor_box = AABB::new(Vec3::new(-50.0, -0.05, -50.0), Vec3::new(50.0, 0.0, 50.0));
+    let floor = Collidable::new(BoundingBox::new(floor_box.min(), floor_box.max()));
+    let obstacles = [(&floor, floor_box)];
+    let body = Collidable::new(BoundingBox::default());
+
+    // Fast enough to pass the floor within one step
+    let mut position = Vec3::new(0.0, 2.0, 0.0);
+    let mut velocity = Vec3::new(2.0, -60.0, 0.0);
+    let gravity = Vec3::new(0.0, -9.81, 0.0);
+    let dt = 1.0 / 60.0;
+    for _ in 0..120 {
+        velocity += gravity * dt;
+        let bbox = body.world_aabb(&Transform::from_translation(position));
+        position += sweep_motion(&bbox, velocity * dt, &mut velocity, &body, &obstacles);
+        // Never sinks into the floor, even while resting on it
+        assert!(position.y - 0.5 >= -SWEEP_TOLERANCE, "{}", position.y);
+    }
+    assert!((position.y - 0.5).abs() < 1e-3);
+    assert!(velocity.y.abs() < 1.0);
+    // and still slides along it
+    assert!(position.x > 3.0);
+}
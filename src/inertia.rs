@@ -0,0 +1,99 @@
+use bevy::prelude::*;
+use crate::narrowphase::Shape;
+
+// Inertia tensors of solid bodies with uniform density, in body space
+// around their center of mass. Round shapes are aligned with the local y
+// axis, like Shape::Capsule.
+
+pub fn box_inertia(mass: f32, half_extents: Vec3) -> Mat3 {
+    let squared = half_extents * half_extents;
+    Mat3::from_diagonal(Vec3::new(
+        squared.y + squared.z,
+        squared.x + squared.z,
+        squared.x + squared.y,
+    ) * (mass / 3.0))
+}
+
+pub fn sphere_inertia(mass: f32, radius: f32) -> Mat3 {
+    Mat3::from_diagonal(Vec3::splat(0.4 * mass * radius * radius))
+}
+
+pub fn cylinder_inertia(mass: f32, radius: f32, half_height: f32) -> Mat3 {
+    let around_axis = 0.5 * mass * radius * radius;
+    let across_axis = mass * (radius * radius / 4.0 + half_height * half_height / 3.0);
+    Mat3::from_diagonal(Vec3::new(across_axis, around_axis, across_axis))
+}
+
+// half_height is the half height of the cylinder between the two caps
+pub fn capsule_inertia(mass: f32, radius: f32, half_height: f32) -> Mat3 {
+    // Split the mass by volume
+    let cylinder_volume = std::f32::consts::PI * radius * radius * 2.0 * half_height;
+    let sphere_volume = 4.0 / 3.0 * std::f32::consts::PI * radius.powi(3);
+    let cylinder_mass = mass * cylinder_volume / (cylinder_volume + sphere_volume);
+    let caps_mass = mass - cylinder_mass;
+
+    let cylinder = cylinder_inertia(cylinder_mass, radius, half_height);
+    // The caps are two hemispheres, moved out to the ends of the cylinder
+    // (parallel axis theorem, their center of mass is 3/8 r from the flat face)
+    let around_axis = 0.4 * caps_mass * radius * radius;
+    let across_axis = caps_mass * (0.4 * radius * radius + half_height * half_height + 0.75 * half_height * radius);
+    cylinder + Mat3::from_diagonal(Vec3::new(across_axis, around_axis, across_axis))
+}
+
+pub fn shape_inertia(shape: &Shape, mass: f32) -> Mat3 {
+    match *shape {
+        Shape::Sphere { radius } => sphere_inertia(mass, radius),
+        Shape::Box { half_extents } => box_inertia(mass, half_extents),
+        Shape::Capsule { half_height, radius } => capsule_inertia(mass, radius, half_height),
+    }
+}
+
+// R I⁻¹ Rᵀ, the inverse tensor of a body rotated by rotation in world space
+pub fn world_inverse_inertia(inverse_body_inertia: &Mat3, rotation: &Quat) -> Mat3 {
+    let rotation = Mat3::from_quat(*rotation);
+    rotation * *inverse_body_inertia * rotation.transpose()
+}
+
+#[cfg(test)]
+fn approx_eq(a: &Mat3, b: &Mat3) -> bool {
+    (*a - *b).to_cols_array().iter().all(|x| x.abs() < 1e-4)
+}
+
+#[test]
+fn test_inertia_shapes() {
+    // A cube is as hard to turn around any axis
+    assert!(approx_eq(&box_inertia(6.0, Vec3::splat(0.5)), &Mat3::IDENTITY));
+    assert!(approx_eq(&sphere_inertia(2.5, 2.0), &Mat3::from_diagonal(Vec3::splat(4.0))));
+
+    // A long box is easy to spin around its long axis
+    let rod = box_inertia(1.0, Vec3::new(0.1, 2.0, 0.1));
+    assert!(rod.y_axis.y < rod.x_axis.x);
+    assert_eq!(rod.x_axis.x, rod.z_axis.z);
+
+    let cylinder = cylinder_inertia(2.0, 1.0, 0.5);
+    assert!(approx_eq(&cylinder, &Mat3::from_diagonal(Vec3::new(2.0 / 3.0, 1.0, 2.0 / 3.0))));
+
+    // A capsule without a cylinder is a sphere
+    assert!(approx_eq(&capsule_inertia(3.0, 0.5, 0.0), &sphere_inertia(3.0, 0.5)));
+    // and lies between the cylinder it contains and the one around it
+    let capsule = capsule_inertia(1.0, 0.5, 1.0);
+    assert!(capsule.x_axis.x > cylinder_inertia(1.0, 0.5, 1.0).x_axis.x);
+    assert!(capsule.x_axis.x < cylinder_inertia(1.0, 0.5, 1.5).x_axis.x);
+
+    let shape = Shape::Box { half_extents: Vec3::splat(0.5) };
+    assert_eq!(shape_inertia(&shape, 6.0), box_inertia(6.0, Vec3::splat(0.5)));
+}
+
+#[test]
+fn test_world_inverse_inertia() {
+    let inverse = box_inertia(1.0, Vec3::new(0.1, 2.0, 0.1)).inverse();
+    assert!(approx_eq(&world_inverse_inertia(&inverse, &Quat::IDENTITY), &inverse));
+
+    // Turned by 90 degrees around z, the long axis points along x
+    let rotation = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+    let world = world_inverse_inertia(&inverse, &rotation);
+    assert!((world.x_axis.x - inverse.y_axis.y).abs() < 1e-3);
+    assert!((world.y_axis.y - inverse.x_axis.x).abs() < 1e-3);
+    // Stays symmetric
+    assert!(approx_eq(&world, &world.transpose()));
+}
@@ -0,0 +1,218 @@
+use bevy::prelude::*;
+#[cfg(test)]
+use crate::inertia::{box_inertia, world_inverse_inertia};
+
+// How the linear motion of RigidBodies is advanced over one step.
+// SemiImplicitEuler updates the velocity first and moves with the new one,
+// it is cheap and keeps the energy of oscillations and orbits bounded.
+// VelocityVerlet averages the accelerations at both ends of the step, it is
+// symplectic as well but second order, free fall is exact.
+// RungeKutta4 samples the acceleration four times per step, it is the most
+// accurate for smooth forces, but its energy slowly drifts over long runs.
+#[derive(Resource, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Integrator {
+    SemiImplicitEuler,
+    VelocityVerlet,
+    RungeKutta4,
+}
+
+impl Default for Integrator {
+    fn default() -> Integrator {
+        Integrator::SemiImplicitEuler
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinearState {
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+impl Integrator {
+    // acceleration is evaluated at (position, velocity), so it can model
+    // springs, drag or attraction as well as constant forces
+    pub fn step(
+        &self,
+        state: &LinearState,
+        dt: f32,
+        acceleration: impl Fn(Vec3, Vec3) -> Vec3,
+    ) -> LinearState {
+        let LinearState { position, velocity } = *state;
+        match self {
+            Integrator::SemiImplicitEuler => {
+                let velocity = velocity + acceleration(position, velocity) * dt;
+                LinearState {
+                    position: position + velocity * dt,
+                    velocity,
+                }
+            }
+            Integrator::VelocityVerlet => {
+                let acc = acceleration(position, velocity);
+                let next_position = position + velocity * dt + acc * (0.5 * dt * dt);
+                // The velocity at the end of the step isn't known yet,
+                // forces that depend on it see a first order estimate
+                let next_acc = acceleration(next_position, velocity + acc * dt);
+                LinearState {
+                    position: next_position,
+                    velocity: velocity + (acc + next_acc) * (0.5 * dt),
+                }
+            }
+            Integrator::RungeKutta4 => {
+                let k1_x = velocity;
+                let k1_v = acceleration(position, velocity);
+                let k2_x = velocity + k1_v * (0.5 * dt);
+                let k2_v = acceleration(position + k1_x * (0.5 * dt), k2_x);
+                let k3_x = velocity + k2_v * (0.5 * dt);
+                let k3_v = acceleration(position + k2_x * (0.5 * dt), k3_x);
+                let k4_x = velocity + k3_v * dt;
+                let k4_v = acceleration(position + k3_x * dt, k4_x);
+                LinearState {
+                    position: position + (k1_x + 2.0 * k2_x + 2.0 * k3_x + k4_x) * (dt / 6.0),
+                    velocity: velocity + (k1_v + 2.0 * k2_v + 2.0 * k3_v + k4_v) * (dt / 6.0),
+                }
+            }
+        }
+    }
+}
+
+// Advances the angular velocity of a body by dt. Without torque a body
+// keeps its angular momentum Iω rather than its angular velocity, unless it
+// spins around a principal axis ω precesses (the gyroscopic term ω × Iω).
+// The gyroscopic term is integrated implicitly with one Newton step, like
+// Bullet does, the explicit version gains energy and blows up at fast spins
+pub fn step_angular_velocity(
+    body_inertia: &Mat3,
+    inverse_body_inertia: &Mat3,
+    rotation: &Quat,
+    angular_velocity: Vec3,
+    torque: Vec3,
+    dt: f32,
+) -> Vec3 {
+    let to_body = rotation.inverse();
+    let omega = to_body * angular_velocity;
+    // Solve I (ω' - ω) + dt ω' × Iω' = 0 for ω', starting at ω
+    let momentum = *body_inertia * omega;
+    let residual = omega.cross(momentum) * dt;
+    let jacobian = *body_inertia + (skew(omega) * *body_inertia - skew(momentum)) * dt;
+    let omega = omega - jacobian.inverse() * residual;
+    let omega = omega + *inverse_body_inertia * (to_body * torque) * dt;
+    *rotation * omega
+}
+
+// Rotates by |ω| dt around ω, which is exact for a constant ω. Normalizing
+// keeps rounding errors from adding up to a scale
+pub fn integrate_rotation(rotation: &Quat, angular_velocity: Vec3, dt: f32) -> Quat {
+    let speed = angular_velocity.length();
+    if speed == 0.0 {
+        return *rotation;
+    }
+    (Quat::from_axis_angle(angular_velocity / speed, speed * dt) * *rotation).normalize()
+}
+
+// skew(a) * b = a × b
+fn skew(v: Vec3) -> Mat3 {
+    Mat3::from_cols(
+        Vec3::new(0.0, v.z, -v.y),
+        Vec3::new(-v.z, 0.0, v.x),
+        Vec3::new(v.y, -v.x, 0.0),
+    )
+}
+
+#[cfg(test)]
+const INTEGRATORS: [Integrator; 3] = [
+    Integrator::SemiImplicitEuler,
+    Integrator::VelocityVerlet,
+    Integrator::RungeKutta4,
+];
+
+#[test]
+fn test_integrator_free_fall_energy() {
+    let g = Vec3::new(0.0, -9.81, 0.0);
+    let energy = |state: &LinearState| 0.5 * state.velocity.length_squared() - g.dot(state.position);
+    let dt = 1.0 / 60.0;
+    let steps = 120;
+    for integrator in INTEGRATORS.iter() {
+        let mut state = LinearState {
+            position: Vec3::new(0.0, 100.0, 0.0),
+            velocity: Vec3::new(2.0, 5.0, 0.0),
+        };
+        let initial_energy = energy(&state);
+        for _ in 0..steps {
+            state = integrator.step(&state, dt, |_, _| g);
+        }
+        let drift = (energy(&state) - initial_energy).abs();
+        match integrator {
+            // Falls half a step's velocity too far every step, so the
+            // error grows linearly with time and shrinks with dt
+            Integrator::SemiImplicitEuler => {
+                let t = steps as f32 * dt;
+                assert!(drift <= 0.5 * g.length_squared() * dt * t * 1.01, "{:?}: {}", integrator, drift);
+                assert!(drift > 0.1);
+            }
+            _ => assert!(drift < 1e-2, "{:?}: {}", integrator, drift),
+        }
+    }
+}
+
+#[test]
+fn test_integrator_orbit_energy() {
+    // A circular orbit of radius 1 around a unit mass, one period is 2 pi
+    let gravity = |position: Vec3, _| -position / position.length().powi(3);
+    let energy = |state: &LinearState| 0.5 * state.velocity.length_squared() - 1.0 / state.position.length();
+    let steps_per_orbit = 200;
+    let dt = std::f32::consts::TAU / steps_per_orbit as f32;
+    for integrator in INTEGRATORS.iter() {
+        let mut state = LinearState {
+            position: Vec3::new(1.0, 0.0, 0.0),
+            velocity: Vec3::new(0.0, 0.0, 1.0),
+        };
+        let initial_energy = energy(&state);
+        for _ in 0..10 * steps_per_orbit {
+            state = integrator.step(&state, dt, gravity);
+            // Never drifts far from the initial energy
+            let tolerance = match integrator {
+                Integrator::SemiImplicitEuler => 2e-2,
+                _ => 1e-3,
+            };
+            assert!((energy(&state) - initial_energy).abs() < tolerance, "{:?}", integrator);
+        }
+        // and stays on the orbit
+        assert!((state.position.length() - 1.0).abs() < 5e-2, "{:?}", integrator);
+    }
+}
+
+#[test]
+fn test_integrate_rotation() {
+    let mut rotation = Quat::IDENTITY;
+    let angular_velocity = Vec3::new(0.0, std::f32::consts::PI, 0.0);
+    for _ in 0..60 {
+        rotation = integrate_rotation(&rotation, angular_velocity, 1.0 / 60.0);
+    }
+    assert!(rotation.angle_between(Quat::from_rotation_y(std::f32::consts::PI)) < 1e-3);
+    assert!((rotation.length() - 1.0).abs() < 1e-6);
+    assert_eq!(integrate_rotation(&rotation, Vec3::ZERO, 1.0), rotation);
+}
+
+#[test]
+fn test_torque_free_rotation_keeps_angular_momentum() {
+    // A box spinning around its major axis, with a wobble
+    let inertia = box_inertia(1.0, Vec3::new(0.2, 0.5, 1.0));
+    let inverse_inertia = inertia.inverse();
+    let world_inertia = |rotation: &Quat| world_inverse_inertia(&inverse_inertia, rotation).inverse();
+
+    let mut rotation = Quat::IDENTITY;
+    let mut angular_velocity = Vec3::new(5.0, 0.5, 0.5);
+    let initial_momentum = world_inertia(&rotation) * angular_velocity;
+    let initial_energy = 0.5 * angular_velocity.dot(initial_momentum);
+    let dt = 1.0 / 60.0;
+    for _ in 0..600 {
+        angular_velocity = step_angular_velocity(&inertia, &inverse_inertia, &rotation, angular_velocity, Vec3::ZERO, dt);
+        rotation = integrate_rotation(&rotation, angular_velocity, dt);
+    }
+    let momentum = world_inertia(&rotation) * angular_velocity;
+    assert!((momentum - initial_momentum).length() < 0.03 * initial_momentum.length());
+    // The implicit step may lose a bit of energy, but never gains any
+    assert!(0.5 * angular_velocity.dot(momentum) <= initial_energy * 1.001);
+    // The wobble makes ω precess around the angular momentum
+    assert!((angular_velocity - Vec3::new(5.0, 0.5, 0.5)).length() > 0.1);
+}
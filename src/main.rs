@@ -12,11 +12,21 @@ mod thruster;
 use thruster::*;
 mod fps_indicator;
 use fps_indicator::*;
-// mod collision_detection;
-// use collision_detection::*;
+mod collision_detection;
+use collision_detection::*;
+mod narrowphase;
+mod contact_solver;
+mod inertia;
+mod integrator;
+mod physics_schedule;
 mod random_moving_balls;
 use random_moving_balls::*;
 mod bvh;
+mod bvh_debug_draw;
+#[cfg(test)]
+mod bvh_test_fixtures;
+mod dynamic_bvh;
+mod spatial_index;
 
 fn setup(
     mut commands: Commands,
@@ -54,9 +64,10 @@ fn main() {
         .add_plugin(PanOrbitCameraPlugin)
         //.add_plugin(TreePlugin)
         //.add_plugin(WeatherPlugin)
-        //.add_plugin(DynamicsPlugin)
+        .add_plugin(DynamicsPlugin)
         //.add_plugin(ThrusterPlugin)
         .add_plugin(RandomMovingBallsPlugin)
+        .add_plugin(CollisionDetectionPlugin)
         .add_plugin(OnScreenFpsPlugin::new(OnScreenFpsConfig {
             style: Style {
                 position_type: PositionType::Absolute,
@@ -0,0 +1,519 @@
+use bevy::prelude::*;
+use crate::bvh::{AABB, OBB};
+
+// Exact contacts between the pairs the broadphase found. Every combination of
+// shapes has its own routine, boxes use the separating axis test with face
+// clipping, the round shapes reduce to the closest points between their cores
+// (a point for spheres, a segment for capsules).
+
+// Shapes are given in the local space of their entity
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Sphere { radius: f32 },
+    Box { half_extents: Vec3 },
+    // A segment along the local y axis from -half_height to half_height,
+    // grown by radius. The tips are at +-(half_height + radius)
+    Capsule { half_height: f32, radius: f32 },
+}
+
+impl Shape {
+    // The local bounding box, e.g. for the broadphase
+    pub fn bounding_box(&self) -> AABB {
+        let half_extents = match *self {
+            Shape::Sphere { radius } => Vec3::splat(radius),
+            Shape::Box { half_extents } => half_extents,
+            Shape::Capsule { half_height, radius } => Vec3::new(radius, half_height + radius, radius),
+        };
+        AABB::new(-half_extents, half_extents)
+    }
+
+    fn in_world(&self, transform: &Transform) -> WorldShape {
+        // Round shapes stay round, so they take the largest scale
+        let scale = transform.scale.abs();
+        match *self {
+            Shape::Sphere { radius } => WorldShape::Sphere {
+                center: transform.translation,
+                radius: radius * scale.max_element(),
+            },
+            Shape::Box { half_extents } => WorldShape::Box(
+                OBB::new(transform.translation, transform.rotation, half_extents * scale)
+            ),
+            Shape::Capsule { half_height, radius } => {
+                let half_segment = transform.rotation * Vec3::new(0.0, half_height * scale.y, 0.0);
+                WorldShape::Capsule {
+                    start: transform.translation - half_segment,
+                    end: transform.translation + half_segment,
+                    radius: radius * scale.x.max(scale.z),
+                }
+            }
+        }
+    }
+}
+
+enum WorldShape {
+    Sphere { center: Vec3, radius: f32 },
+    Box(OBB),
+    Capsule { start: Vec3, end: Vec3, radius: f32 },
+}
+
+pub const MAX_CONTACT_POINTS: usize = 4;
+
+// Contact between two shapes a and b. The normal has unit length and points
+// from a to b, moving b by normal * depth separates them. The points lie
+// halfway between both surfaces, there are at most MAX_CONTACT_POINTS
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContactManifold {
+    pub normal: Vec3,
+    pub depth: f32,
+    pub points: Vec<Vec3>,
+}
+
+impl ContactManifold {
+    // The same contact, seen from b
+    pub fn flipped(self) -> ContactManifold {
+        ContactManifold {
+            normal: -self.normal,
+            ..self
+        }
+    }
+}
+
+// None if the shapes don't touch
+pub fn contact(
+    shape_a: &Shape,
+    transform_a: &Transform,
+    shape_b: &Shape,
+    transform_b: &Transform,
+) -> Option<ContactManifold> {
+    use WorldShape::*;
+    match (shape_a.in_world(transform_a), shape_b.in_world(transform_b)) {
+        (Sphere { center: center_a, radius: radius_a }, Sphere { center: center_b, radius: radius_b }) => {
+            sphere_sphere(center_a, radius_a, center_b, radius_b)
+        }
+        (Sphere { center, radius }, Capsule { start, end, radius: capsule_radius }) => {
+            let closest = closest_point_on_segment(&center, &start, &end);
+            sphere_sphere(center, radius, closest, capsule_radius)
+        }
+        (Capsule { .. }, Sphere { .. }) => contact(shape_b, transform_b, shape_a, transform_a).map(ContactManifold::flipped),
+        (Capsule { start: start_a, end: end_a, radius: radius_a }, Capsule { start: start_b, end: end_b, radius: radius_b }) => {
+            let (closest_a, closest_b) = closest_points_on_segments(&start_a, &end_a, &start_b, &end_b);
+            sphere_sphere(closest_a, radius_a, closest_b, radius_b)
+        }
+        (Sphere { center, radius }, Box(obb)) => sphere_box(&center, radius, &obb),
+        (Box(_), Sphere { .. }) => contact(shape_b, transform_b, shape_a, transform_a).map(ContactManifold::flipped),
+        (Capsule { start, end, radius }, Box(obb)) => capsule_box(&start, &end, radius, &obb),
+        (Box(_), Capsule { .. }) => contact(shape_b, transform_b, shape_a, transform_a).map(ContactManifold::flipped),
+        (Box(obb_a), Box(obb_b)) => box_box(&obb_a, &obb_b),
+    }
+}
+
+fn sphere_sphere(center_a: Vec3, radius_a: f32, center_b: Vec3, radius_b: f32) -> Option<ContactManifold> {
+    let offset = center_b - center_a;
+    let distance = offset.length();
+    if distance > radius_a + radius_b {
+        return None;
+    }
+    // Concentric spheres can be pushed apart in any direction
+    let normal = if distance > f32::EPSILON { offset / distance } else { Vec3::Y };
+    let depth = radius_a + radius_b - distance;
+    Some(ContactManifold {
+        normal,
+        depth,
+        points: vec![center_a + normal * (radius_a - depth / 2.0)],
+    })
+}
+
+fn sphere_box(center: &Vec3, radius: f32, obb: &OBB) -> Option<ContactManifold> {
+    let half_extents = obb.half_extents();
+    let offset = *center - obb.center();
+    let local = Vec3::new(offset.dot(obb.axis(0)), offset.dot(obb.axis(1)), offset.dot(obb.axis(2)));
+    let clamped = local.clamp(-half_extents, half_extents);
+
+    if clamped != local {
+        // The center is outside, the closest point is on the surface
+        let closest = obb.center() + obb.axis(0) * clamped.x + obb.axis(1) * clamped.y + obb.axis(2) * clamped.z;
+        let to_box = closest - *center;
+        let distance = to_box.length();
+        if distance > radius {
+            return None;
+        }
+        let normal = to_box / distance;
+        return Some(ContactManifold {
+            normal,
+            depth: radius - distance,
+            points: vec![(*center + normal * radius + closest) / 2.0],
+        });
+    }
+
+    // The center is inside, push the sphere out through the closest face
+    let face_distances = half_extents - local.abs();
+    let axis = if face_distances.x <= face_distances.y && face_distances.x <= face_distances.z {
+        0
+    } else if face_distances.y <= face_distances.z {
+        1
+    } else {
+        2
+    };
+    // The sphere leaves towards the face, so the box has to move the other way
+    let normal = -obb.axis(axis) * local[axis].signum();
+    Some(ContactManifold {
+        normal,
+        depth: radius + face_distances[axis],
+        points: vec![*center],
+    })
+}
+
+// Tests the ends of the capsule and the point of its segment closest to the
+// box, so a capsule lying flat on a face gets a contact at both ends
+fn capsule_box(start: &Vec3, end: &Vec3, radius: f32, obb: &OBB) -> Option<ContactManifold> {
+    // Alternating projections converge to the closest points
+    // of the segment and the box, both being convex
+    let mut closest = closest_point_on_segment(&obb.center(), start, end);
+    for _ in 0..4 {
+        closest = closest_point_on_segment(&obb_closest_point(obb, &closest), start, end);
+    }
+
+    let mut deepest: Option<ContactManifold> = None;
+    let mut points: Vec<Vec3> = Vec::new();
+    for candidate in [*start, *end, closest] {
+        if let Some(manifold) = sphere_box(&candidate, radius, obb) {
+            let point = manifold.points[0];
+            if points.iter().all(|p| p.distance(point) > 1e-4) {
+                points.push(point);
+            }
+            if deepest.as_ref().map_or(true, |deepest| manifold.depth > deepest.depth) {
+                deepest = Some(manifold);
+            }
+        }
+    }
+    deepest.map(|deepest| ContactManifold { points, ..deepest })
+}
+
+fn obb_closest_point(obb: &OBB, point: &Vec3) -> Vec3 {
+    let offset = *point - obb.center();
+    let mut closest = obb.center();
+    for i in 0..3 {
+        let extent = obb.half_extents()[i];
+        closest += obb.axis(i) * offset.dot(obb.axis(i)).clamp(-extent, extent);
+    }
+    closest
+}
+
+#[derive(Clone, Copy)]
+enum SeparatingAxis {
+    FaceA(usize),
+    FaceB(usize),
+    Edges(usize, usize),
+}
+
+// Separating axis test like OBB::intersects, but it keeps the axis with the
+// smallest overlap. That is the contact normal, the contact points come from
+// clipping the faces of the boxes against each other
+fn box_box(a: &OBB, b: &OBB) -> Option<ContactManifold> {
+    let offset = b.center() - a.center();
+    let overlap_on = |axis: Vec3| {
+        a.half_extents().dot(Vec3::new(a.axis(0).dot(axis).abs(), a.axis(1).dot(axis).abs(), a.axis(2).dot(axis).abs()))
+            + b.half_extents().dot(Vec3::new(b.axis(0).dot(axis).abs(), b.axis(1).dot(axis).abs(), b.axis(2).dot(axis).abs()))
+            - offset.dot(axis).abs()
+    };
+
+    let mut best: Option<(f32, Vec3, SeparatingAxis)> = None;
+    for i in 0..3 {
+        for (axis, kind) in [(a.axis(i), SeparatingAxis::FaceA(i)), (b.axis(i), SeparatingAxis::FaceB(i))] {
+            let overlap = overlap_on(axis);
+            if overlap < 0.0 {
+                return None;
+            }
+            if best.map_or(true, |(best_overlap, _, _)| overlap < best_overlap) {
+                best = Some((overlap, axis, kind));
+            }
+        }
+    }
+    for i in 0..3 {
+        for j in 0..3 {
+            let axis = a.axis(i).cross(b.axis(j));
+            // Parallel edges, their axis is covered by the face normals
+            if axis.length_squared() < 1e-6 {
+                continue;
+            }
+            let axis = axis.normalize();
+            let overlap = overlap_on(axis);
+            if overlap < 0.0 {
+                return None;
+            }
+            // Only prefer edges when they are clearly better,
+            // face contacts give more stable manifolds
+            if overlap < best.unwrap().0 * 0.95 - 1e-4 {
+                best = Some((overlap, axis, SeparatingAxis::Edges(i, j)));
+            }
+        }
+    }
+
+    let (depth, axis, kind) = best.unwrap();
+    let normal = if offset.dot(axis) < 0.0 { -axis } else { axis };
+    let points = match kind {
+        SeparatingAxis::FaceA(i) => clip_faces(a, i, normal, b),
+        SeparatingAxis::FaceB(i) => clip_faces(b, i, -normal, a),
+        SeparatingAxis::Edges(i, j) => {
+            let (edge_a_start, edge_a_end) = support_edge(a, i, normal);
+            let (edge_b_start, edge_b_end) = support_edge(b, j, -normal);
+            let (closest_a, closest_b) = closest_points_on_segments(&edge_a_start, &edge_a_end, &edge_b_start, &edge_b_end);
+            vec![(closest_a + closest_b) / 2.0]
+        }
+    };
+    Some(ContactManifold { normal, depth, points })
+}
+
+// The edge of the box along axis i that lies furthest in direction
+fn support_edge(obb: &OBB, i: usize, direction: Vec3) -> (Vec3, Vec3) {
+    let mut middle = obb.center();
+    for k in 0..3 {
+        if k != i {
+            middle += obb.axis(k) * obb.half_extents()[k] * obb.axis(k).dot(direction).signum();
+        }
+    }
+    let half_edge = obb.axis(i) * obb.half_extents()[i];
+    (middle - half_edge, middle + half_edge)
+}
+
+// Clips the face of the incident box that faces the reference face
+// against the sides of the reference face. The reference face is the
+// one of axis i, on the side that normal points to
+fn clip_faces(reference: &OBB, i: usize, normal: Vec3, incident: &OBB) -> Vec<Vec3> {
+    // The incident face is the one most anti-parallel to the normal
+    let m = (0..3)
+        .max_by(|k, l| incident.axis(*k).dot(normal).abs().total_cmp(&incident.axis(*l).dot(normal).abs()))
+        .unwrap();
+    let incident_face_center = incident.center()
+        - incident.axis(m) * incident.half_extents()[m] * incident.axis(m).dot(normal).signum();
+    let u = incident.axis((m + 1) % 3) * incident.half_extents()[(m + 1) % 3];
+    let v = incident.axis((m + 2) % 3) * incident.half_extents()[(m + 2) % 3];
+    let mut polygon = vec![
+        incident_face_center + u + v,
+        incident_face_center - u + v,
+        incident_face_center - u - v,
+        incident_face_center + u - v,
+    ];
+
+    // Sutherland-Hodgman against the 4 side planes of the reference face
+    for side in [(i + 1) % 3, (i + 2) % 3] {
+        for sign in [1.0, -1.0] {
+            let plane_normal = reference.axis(side) * sign;
+            let plane_offset = plane_normal.dot(reference.center()) + reference.half_extents()[side];
+            polygon = clip_polygon(&polygon, plane_normal, plane_offset);
+        }
+    }
+
+    let face_center = reference.center() + normal * reference.half_extents()[i];
+    let mut points: Vec<(Vec3, f32)> = polygon
+        .into_iter()
+        .filter_map(|point| {
+            let separation = (point - face_center).dot(normal);
+            // Halfway between the incident point and its projection onto the reference face
+            (separation <= 0.0).then(|| (point - normal * separation / 2.0, -separation))
+        })
+        .collect();
+    if points.len() > MAX_CONTACT_POINTS {
+        points = reduce_contact_points(points, reference.axis((i + 1) % 3), reference.axis((i + 2) % 3));
+    }
+    points.into_iter().map(|(point, _)| point).collect()
+}
+
+// Keeps the points of polygon with point.dot(plane_normal) <= plane_offset
+fn clip_polygon(polygon: &[Vec3], plane_normal: Vec3, plane_offset: f32) -> Vec<Vec3> {
+    let mut clipped = Vec::with_capacity(polygon.len() + 1);
+    for (k, current) in polygon.iter().enumerate() {
+        let next = polygon[(k + 1) % polygon.len()];
+        let current_distance = current.dot(plane_normal) - plane_offset;
+        let next_distance = next.dot(plane_normal) - plane_offset;
+        if current_distance <= 0.0 {
+            clipped.push(*current);
+        }
+        // The edge crosses the plane
+        if (current_distance <= 0.0) != (next_distance <= 0.0) {
+            let t = current_distance / (current_distance - next_distance);
+            clipped.push(current.lerp(next, t));
+        }
+    }
+    clipped
+}
+
+// Keeps the deepest point and the points spanning the largest area
+// in the plane of the reference face, which keeps the manifold stable
+fn reduce_contact_points(points: Vec<(Vec3, f32)>, u: Vec3, v: Vec3) -> Vec<(Vec3, f32)> {
+    let deepest = points.iter().cloned().max_by(|a, b| a.1.total_cmp(&b.1)).unwrap();
+    let mut reduced = vec![deepest];
+    for direction in [u + v, u - v, -u - v, -u + v] {
+        let extreme = points
+            .iter()
+            .cloned()
+            .max_by(|a, b| a.0.dot(direction).total_cmp(&b.0.dot(direction)))
+            .unwrap();
+        if reduced.len() < MAX_CONTACT_POINTS && reduced.iter().all(|p| p.0.distance(extreme.0) > 1e-4) {
+            reduced.push(extreme);
+        }
+    }
+    reduced
+}
+
+fn closest_point_on_segment(point: &Vec3, start: &Vec3, end: &Vec3) -> Vec3 {
+    let segment = *end - *start;
+    let length_squared = segment.length_squared();
+    if length_squared < f32::EPSILON {
+        return *start;
+    }
+    let t = ((*point - *start).dot(segment) / length_squared).clamp(0.0, 1.0);
+    *start + segment * t
+}
+
+// After Ericson, Real-Time Collision Detection, 5.1.9
+fn closest_points_on_segments(start_a: &Vec3, end_a: &Vec3, start_b: &Vec3, end_b: &Vec3) -> (Vec3, Vec3) {
+    let d_a = *end_a - *start_a;
+    let d_b = *end_b - *start_b;
+    let r = *start_a - *start_b;
+    let a = d_a.length_squared();
+    let e = d_b.length_squared();
+    let f = d_b.dot(r);
+    if a < f32::EPSILON && e < f32::EPSILON {
+        return (*start_a, *start_b);
+    }
+    if a < f32::EPSILON {
+        return (*start_a, closest_point_on_segment(start_a, start_b, end_b));
+    }
+    let c = d_a.dot(r);
+    if e < f32::EPSILON {
+        return (closest_point_on_segment(start_b, start_a, end_a), *start_b);
+    }
+    let b = d_a.dot(d_b);
+    let denominator = a * e - b * b;
+    // Parallel segments have no unique closest points, any s works
+    let mut s = if denominator > f32::EPSILON { ((b * f - c * e) / denominator).clamp(0.0, 1.0) } else { 0.0 };
+    let mut t = (b * s + f) / e;
+    if t < 0.0 {
+        t = 0.0;
+        s = (-c / a).clamp(0.0, 1.0);
+    } else if t > 1.0 {
+        t = 1.0;
+        s = ((b - c) / a).clamp(0.0, 1.0);
+    }
+    (*start_a + d_a * s, *start_b + d_b * t)
+}
+
+#[test]
+fn test_sphere_sphere() {
+    let sphere = Shape::Sphere { radius: 1.0 };
+    let manifold = contact(
+        &sphere, &Transform::default(),
+        &sphere, &Transform::from_translation(Vec3::new(1.5, 0.0, 0.0)),
+    ).unwrap();
+    assert_eq!(manifold.normal, Vec3::X);
+    assert_eq!(manifold.depth, 0.5);
+    assert_eq!(manifold.points, vec![Vec3::new(0.75, 0.0, 0.0)]);
+
+    assert!(contact(
+        &sphere, &Transform::default(),
+        &sphere, &Transform::from_translation(Vec3::new(2.5, 0.0, 0.0)),
+    ).is_none());
+}
+
+#[test]
+fn test_sphere_box() {
+    let sphere = Shape::Sphere { radius: 0.5 };
+    let unit_box = Shape::Box { half_extents: Vec3::splat(1.0) };
+    // Above the top face
+    let manifold = contact(
+        &sphere, &Transform::from_translation(Vec3::new(0.2, 1.25, 0.0)),
+        &unit_box, &Transform::default(),
+    ).unwrap();
+    assert!((manifold.normal - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-5);
+    assert!((manifold.depth - 0.25).abs() < 1e-5);
+
+    // The center is inside the box, close to the +x face
+    let manifold = contact(
+        &unit_box, &Transform::default(),
+        &sphere, &Transform::from_translation(Vec3::new(0.8, 0.0, 0.0)),
+    ).unwrap();
+    assert!((manifold.normal - Vec3::X).length() < 1e-5);
+    assert!((manifold.depth - 0.7).abs() < 1e-5);
+
+    // Off the corner of a rotated box
+    let rotated = Transform::from_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4));
+    assert!(contact(&sphere, &Transform::from_translation(Vec3::new(1.2, 1.2, 0.0)), &unit_box, &rotated).is_none());
+}
+
+#[test]
+fn test_capsule_capsule() {
+    let capsule = Shape::Capsule { half_height: 1.0, radius: 0.25 };
+    // Crossing at a right angle, 0.4 apart
+    let lying = Transform::from_translation(Vec3::new(0.0, 0.0, 0.4))
+        .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
+    let manifold = contact(&capsule, &Transform::default(), &capsule, &lying).unwrap();
+    assert!((manifold.normal - Vec3::Z).length() < 1e-5);
+    assert!((manifold.depth - 0.1).abs() < 1e-5);
+    assert!((manifold.points[0] - Vec3::new(0.0, 0.0, 0.2)).length() < 1e-5);
+}
+
+#[test]
+fn test_capsule_box() {
+    let capsule = Shape::Capsule { half_height: 1.0, radius: 0.25 };
+    let ground = Shape::Box { half_extents: Vec3::new(5.0, 0.5, 5.0) };
+    // Lying on the ground, sunk in by 0.05
+    let lying = Transform::from_translation(Vec3::new(0.0, 0.7, 0.0))
+        .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_2));
+    let manifold = contact(&capsule, &lying, &ground, &Transform::default()).unwrap();
+    assert!((manifold.normal - Vec3::new(0.0, -1.0, 0.0)).length() < 1e-5);
+    assert!((manifold.depth - 0.05).abs() < 1e-5);
+    // Both ends touch
+    assert!(manifold.points.len() >= 2);
+    assert!(manifold.points.iter().any(|p| p.x < -0.9) && manifold.points.iter().any(|p| p.x > 0.9));
+}
+
+#[test]
+fn test_box_box_face() {
+    let small_box = Shape::Box { half_extents: Vec3::splat(0.5) };
+    let ground = Shape::Box { half_extents: Vec3::new(5.0, 0.5, 5.0) };
+    // Resting on the ground, sunk in by 0.1
+    let manifold = contact(
+        &ground, &Transform::default(),
+        &small_box, &Transform::from_translation(Vec3::new(1.0, 0.9, 0.0)),
+    ).unwrap();
+    assert!((manifold.normal - Vec3::Y).length() < 1e-5);
+    assert!((manifold.depth - 0.1).abs() < 1e-5);
+    assert_eq!(manifold.points.len(), 4);
+    for point in manifold.points.iter() {
+        assert!((point.y - 0.45).abs() < 1e-5);
+        assert!((point.x - 1.0).abs() <= 0.5 + 1e-5 && point.z.abs() <= 0.5 + 1e-5);
+    }
+
+    // Seen from the other box
+    let flipped = contact(
+        &small_box, &Transform::from_translation(Vec3::new(1.0, 0.9, 0.0)),
+        &ground, &Transform::default(),
+    ).unwrap();
+    assert!((flipped.normal + Vec3::Y).length() < 1e-5);
+    assert_eq!(flipped.points.len(), 4);
+}
+
+#[test]
+fn test_box_box_rotated() {
+    let unit_box = Shape::Box { half_extents: Vec3::splat(0.5) };
+    // Turned around y by 45 degrees, the clipped face has 8 corners
+    let turned = Transform::from_translation(Vec3::new(0.0, 0.9, 0.0))
+        .with_rotation(Quat::from_rotation_y(std::f32::consts::FRAC_PI_4));
+    let manifold = contact(&unit_box, &Transform::default(), &unit_box, &turned).unwrap();
+    assert!((manifold.normal - Vec3::Y).length() < 1e-5);
+    assert!(manifold.points.len() <= MAX_CONTACT_POINTS && manifold.points.len() >= 3);
+
+    // Edge on edge, both boxes tilted so their edges cross
+    let tilted_a = Transform::from_rotation(Quat::from_rotation_x(std::f32::consts::FRAC_PI_4));
+    let tilted_b = Transform::from_translation(Vec3::new(0.0, 1.3, 0.0))
+        .with_rotation(Quat::from_rotation_z(std::f32::consts::FRAC_PI_4));
+    let manifold = contact(&unit_box, &tilted_a, &unit_box, &tilted_b).unwrap();
+    assert!((manifold.normal - Vec3::Y).length() < 1e-4);
+    assert!((manifold.depth - (2f32.sqrt() - 1.3)).abs() < 1e-4);
+    assert_eq!(manifold.points.len(), 1);
+    assert!((manifold.points[0] - Vec3::new(0.0, 0.65, 0.0)).length() < 1e-3);
+
+    let apart = Transform::from_translation(Vec3::new(0.0, 1.5, 0.0));
+    assert!(contact(&unit_box, &tilted_a, &unit_box, &apart.with_rotation(tilted_b.rotation)).is_none());
+}
@@ -0,0 +1,224 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*, transform::TransformSystem};
+use crate::dynamics::RigidBody;
+
+// Runs the physics at a fixed rate, independent of the frame rate, so the
+// simulation behaves the same on every machine and is reproducible.
+// The frame time is collected in an accumulator, PhysicsStage runs once for
+// every substep that fits into it. Each step of timestep seconds is split
+// into substeps, the whole pipeline (collision detection and dynamics) runs
+// per substep, which makes stacks and fast bodies more stable.
+//
+// The pose of a RigidBody lives in its PhysicsState. While PhysicsStage runs,
+// Transform holds the physics pose, so collision detection can keep working
+// with Transforms. Afterwards Transform is set to an interpolation between
+// the last two physics poses, so the motion looks smooth even if the frame
+// rate isn't a multiple of the physics rate. Move bodies through their
+// PhysicsState, changes to their Transform get overwritten.
+pub struct PhysicsSchedulePlugin;
+impl Plugin for PhysicsSchedulePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<PhysicsSchedule>()
+            .add_stage_after(
+                CoreStage::Update,
+                PhysicsStage,
+                SystemStage::parallel().with_run_criteria(run_physics_substep),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, add_physics_state)
+            .add_system_to_stage(PhysicsStage, begin_physics_substep.label(PhysicsSystem::Begin))
+            .add_system_to_stage(
+                PhysicsStage,
+                end_physics_substep
+                    .label(PhysicsSystem::End)
+                    .after(PhysicsSystem::Begin)
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                interpolate_transforms.before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+#[derive(StageLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PhysicsStage;
+
+// Every system in PhysicsStage that reads or writes the physics pose
+// has to run between Begin and End
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PhysicsSystem {
+    Begin,
+    End,
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct PhysicsSchedule {
+    // Seconds per step, 60 Hz by default
+    pub timestep: f32,
+    pub substeps: u32,
+    // A frame that took very long would otherwise need so many steps that
+    // the next frame takes even longer. The time beyond this is dropped
+    pub max_steps_per_frame: u32,
+    accumulator: f32,
+    // Whether the run criteria has already been asked this frame
+    looping: bool,
+}
+
+impl Default for PhysicsSchedule {
+    fn default() -> PhysicsSchedule {
+        PhysicsSchedule {
+            timestep: 1.0 / 60.0,
+            substeps: 1,
+            max_steps_per_frame: 5,
+            accumulator: 0.0,
+            looping: false,
+        }
+    }
+}
+
+impl PhysicsSchedule {
+    // The dt that systems in PhysicsStage have to use instead of Time
+    pub fn delta_seconds(&self) -> f32 {
+        self.timestep / self.substeps.max(1) as f32
+    }
+
+    fn accumulate(&mut self, seconds: f32) {
+        let max_accumulated = self.timestep * self.max_steps_per_frame as f32;
+        self.accumulator = (self.accumulator + seconds).min(max_accumulated);
+    }
+
+    // Takes one substep out of the accumulator, if there is enough time left
+    fn consume_substep(&mut self) -> bool {
+        if self.accumulator >= self.delta_seconds() {
+            self.accumulator -= self.delta_seconds();
+            return true;
+        }
+        false
+    }
+
+    // How far the time of the frame is between the previous and the current
+    // physics pose, in [0, 1)
+    pub fn interpolation_alpha(&self) -> f32 {
+        self.accumulator / self.delta_seconds()
+    }
+}
+
+// The authoritative pose of a RigidBody, see PhysicsSchedulePlugin
+#[derive(Component, Clone, Copy, Debug, PartialEq)]
+pub struct PhysicsState {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    previous_translation: Vec3,
+    previous_rotation: Quat,
+}
+
+impl PhysicsState {
+    pub fn from_transform(transform: &Transform) -> PhysicsState {
+        PhysicsState {
+            translation: transform.translation,
+            rotation: transform.rotation,
+            previous_translation: transform.translation,
+            previous_rotation: transform.rotation,
+        }
+    }
+
+    fn interpolated(&self, alpha: f32) -> (Vec3, Quat) {
+        (
+            self.previous_translation.lerp(self.translation, alpha),
+            self.previous_rotation.slerp(self.rotation, alpha),
+        )
+    }
+}
+
+fn run_physics_substep(time: Res<Time>, mut schedule: ResMut<PhysicsSchedule>) -> ShouldRun {
+    // The run criteria is evaluated again after every run of the stage,
+    // only the first evaluation of a frame adds the frame time
+    if !schedule.looping {
+        schedule.accumulate(time.delta_seconds());
+    }
+    if schedule.consume_substep() {
+        schedule.looping = true;
+        ShouldRun::YesAndCheckAgain
+    } else {
+        schedule.looping = false;
+        ShouldRun::No
+    }
+}
+
+// Bodies spawned with only a Transform start where they were placed
+fn add_physics_state(
+    mut commands: Commands,
+    query: Query<(Entity, &Transform), (With<RigidBody>, Without<PhysicsState>)>,
+) {
+    for (entity, transform) in query.iter() {
+        commands.entity(entity).insert(PhysicsState::from_transform(transform));
+    }
+}
+
+fn begin_physics_substep(mut query: Query<(&mut Transform, &mut PhysicsState)>) {
+    for (mut transform, mut state) in query.iter_mut() {
+        state.previous_translation = state.translation;
+        state.previous_rotation = state.rotation;
+        transform.translation = state.translation;
+        transform.rotation = state.rotation;
+    }
+}
+
+fn end_physics_substep(mut query: Query<(&Transform, &mut PhysicsState)>) {
+    for (transform, mut state) in query.iter_mut() {
+        state.translation = transform.translation;
+        state.rotation = transform.rotation;
+    }
+}
+
+fn interpolate_transforms(
+    schedule: Res<PhysicsSchedule>,
+    mut query: Query<(&mut Transform, &PhysicsState)>,
+) {
+    let alpha = schedule.interpolation_alpha();
+    for (mut transform, state) in query.iter_mut() {
+        let (translation, rotation) = state.interpolated(alpha);
+        transform.translation = translation;
+        transform.rotation = rotation;
+    }
+}
+
+#[test]
+fn test_physics_schedule_accumulator() {
+    // Powers of two, so the sums are exact
+    let mut schedule = PhysicsSchedule {
+        timestep: 0.5,
+        substeps: 2,
+        max_steps_per_frame: 3,
+        ..Default::default()
+    };
+    assert_eq!(schedule.delta_seconds(), 0.25);
+
+    // A frame of 0.625 s fits two substeps, the rest carries over
+    schedule.accumulate(0.625);
+    assert!(schedule.consume_substep());
+    assert!(schedule.consume_substep());
+    assert!(!schedule.consume_substep());
+    assert_eq!(schedule.interpolation_alpha(), 0.5);
+
+    // The next frame gets the remainder
+    schedule.accumulate(0.125);
+    assert!(schedule.consume_substep());
+    assert!(!schedule.consume_substep());
+
+    // A very long frame is capped at max_steps_per_frame steps
+    schedule.accumulate(10.0);
+    let mut substeps = 0;
+    while schedule.consume_substep() {
+        substeps += 1;
+    }
+    assert_eq!(substeps, 6);
+}
+
+#[test]
+fn test_physics_state_interpolation() {
+    let mut state = PhysicsState::from_transform(&Transform::default());
+    state.translation = Vec3::new(2.0, 0.0, 0.0);
+    state.rotation = Quat::from_rotation_y(1.0);
+    let (translation, rotation) = state.interpolated(0.25);
+    assert!((translation - Vec3::new(0.5, 0.0, 0.0)).length() < 1e-5);
+    assert!(rotation.angle_between(Quat::from_rotation_y(0.25)) < 1e-4);
+}
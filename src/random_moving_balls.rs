@@ -9,12 +9,16 @@ use bevy::{
     },
 };
 use rand::random;
-use crate::bvh::{BVHNode, AABB};
+use crate::bvh_debug_draw::BVHDebugDrawPlugin;
+use crate::spatial_index::{SpatialIndexPlugin, SpatialQuery};
 
 pub struct RandomMovingBallsPlugin;
 impl Plugin for RandomMovingBallsPlugin {
     fn build(&self, app: &mut App) {
         app.add_asset::<MyMaterial>()
+            .add_plugin(SpatialIndexPlugin::<RandomMovingBall>::default())
+            // Press B to show the boxes of the index, N to step through its levels
+            .add_plugin(BVHDebugDrawPlugin::<RandomMovingBall>::default())
             .add_startup_system(setup_transparent_material)
             .add_startup_system(spawn_balls)
             .add_system(move_balls)
@@ -117,10 +121,11 @@ fn move_balls(
 }
 
 fn test_color_balls_bvs(
+    balls: SpatialQuery<RandomMovingBall>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut query_set: QuerySet<(
-        QueryState<(Entity, &Transform, &mut Handle<StandardMaterial>), (With<RandomMovingBall>, Without<FocusBall>)>,
-        QueryState<(&Transform, &mut Handle<StandardMaterial>), With<FocusBall>>,
+        QueryState<&mut Handle<StandardMaterial>, (With<RandomMovingBall>, Without<FocusBall>)>,
+        QueryState<(Entity, &Transform, &mut Handle<StandardMaterial>), With<FocusBall>>,
     )>
 
 ) {
@@ -129,35 +134,35 @@ fn test_color_balls_bvs(
     let blue_handle = materials.add(Color::BLUE.into());
     let neutral_handle = materials.add(Color::rgb(1.0, 0.9, 0.9).into());
 
-    // initialize bvh
-    let bbox = AABB::new(Vec3::splat(-0.125), Vec3::splat(0.125));
-    let mut data_and_boxes: Vec<(Entity, AABB)> = Vec::new();
-    for (e, transform, mut material) in query_set.q0_mut().iter_mut() {
-        data_and_boxes.push((e, bbox.translated(&transform.translation)));
+    for mut material in query_set.q0_mut().iter_mut() {
         *material = neutral_handle.clone();
     }
-    let root = BVHNode::create(data_and_boxes).unwrap();
 
+    let mut focus_ball = None;
     let mut focus_ball_position = Vec3::zero();
-    for (transform, mut material) in query_set.q1_mut().iter_mut() {
+    for (e, transform, mut material) in query_set.q1_mut().iter_mut() {
+        focus_ball = Some(e);
         focus_ball_position = transform.translation.clone();
         *material = green_handle.clone();
     }
     
-    if let Some(entities) = root.get_in_radius(&focus_ball_position, 2.0){
-        for e in entities.iter() {
-            let mut material = query_set.q0_mut().get_component_mut::<Handle<StandardMaterial>>(*e).unwrap();    
+    // The closest ball is red, its nearest neighbours are blue.
+    // The focus ball is in the index as well, so skip it
+    let closest: Vec<(Entity, f32)> = balls.n_nearest(&focus_ball_position, 7)
+        .into_iter()
+        .filter(|(e, _distance)| Some(*e) != focus_ball)
+        .take(6)
+        .collect();
+    if closest.is_empty() {
+        println!("No closest entity found");
+    }
+    for (i, (e, _distance)) in closest.iter().enumerate() {
+        let mut material = query_set.q0_mut().get_component_mut::<Handle<StandardMaterial>>(*e).unwrap();
+        if i == 0 {
+            *material = red_handle.clone();
+        } else {
             *material = blue_handle.clone();
         }
-    } else {
-        println!("No entities in radius");
-    }
-    
-    if let Some(closest_entity) = root.get_closest(&focus_ball_position){
-        let mut material = query_set.q0_mut().get_component_mut::<Handle<StandardMaterial>>(closest_entity.0).unwrap();    
-        *material = red_handle.clone();
-    } else {
-        println!("No closest entity found");
     }
 }
 
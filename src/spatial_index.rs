@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::*,
+    render::{primitives::Aabb, view::VisibilitySystems},
+    transform::TransformSystem,
+};
+use crate::bvh::AABB;
+use crate::dynamic_bvh::{DynamicBVH, DynamicBVHHandle};
+
+// Keeps a BVH of all entities with the marker component M, so any plugin can
+// do spatial lookups through SpatialQuery<M> without building its own tree.
+// Entities with a mesh are indexed with their Aabb, others as a point at
+// their translation.
+//
+// The index is updated in CoreStage::PostUpdate after transforms and bounds
+// have been computed, so during Update it reflects the end of the previous frame.
+pub struct SpatialIndexPlugin<M: Component> {
+    marker: PhantomData<M>,
+}
+
+impl<M: Component> Default for SpatialIndexPlugin<M> {
+    fn default() -> Self {
+        SpatialIndexPlugin { marker: PhantomData }
+    }
+}
+
+impl<M: Component> Plugin for SpatialIndexPlugin<M> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpatialIndex<M>>()
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                update_spatial_index::<M>
+                    .label(SpatialIndexSystem::Update)
+                    .after(TransformSystem::TransformPropagate)
+                    .after(VisibilitySystems::CalculateBounds),
+            );
+    }
+}
+
+#[derive(SystemLabel, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SpatialIndexSystem {
+    Update,
+}
+
+#[derive(Resource)]
+pub struct SpatialIndex<M: Component> {
+    bvh: DynamicBVH<Entity>,
+    handles: HashMap<Entity, DynamicBVHHandle>,
+    marker: PhantomData<M>,
+}
+
+impl<M: Component> Default for SpatialIndex<M> {
+    fn default() -> Self {
+        SpatialIndex {
+            bvh: DynamicBVH::default(),
+            handles: HashMap::new(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<M: Component> SpatialIndex<M> {
+    pub fn bvh(&self) -> &DynamicBVH<Entity> {
+        &self.bvh
+    }
+
+    // Whether the tree has changed
+    fn insert_or_update(&mut self, entity: Entity, bbox: AABB) -> bool {
+        if let Some(handle) = self.handles.get(&entity) {
+            self.bvh.update(*handle, bbox).unwrap_or(false)
+        } else {
+            let handle = self.bvh.insert(entity, bbox);
+            self.handles.insert(entity, handle);
+            true
+        }
+    }
+
+    fn remove(&mut self, entity: Entity) -> bool {
+        match self.handles.remove(&entity) {
+            Some(handle) => self.bvh.remove(handle).is_some(),
+            None => false,
+        }
+    }
+}
+
+fn world_aabb(transform: &GlobalTransform, aabb: Option<&Aabb>) -> AABB {
+    match aabb {
+        Some(aabb) => {
+            let center = Vec3::from(aabb.center);
+            let half_extents = Vec3::from(aabb.half_extents);
+            AABB::new(center - half_extents, center + half_extents)
+                .transformed(&transform.compute_transform())
+        }
+        None => AABB::new(transform.translation(), transform.translation()),
+    }
+}
+
+fn update_spatial_index<M: Component>(
+    mut index: ResMut<SpatialIndex<M>>,
+    removed: RemovedComponents<M>,
+    query: Query<
+        (Entity, &GlobalTransform, Option<&Aabb>),
+        (With<M>, Or<(Changed<GlobalTransform>, Changed<Aabb>, Added<M>)>),
+    >,
+) {
+    // The index only counts as changed if the tree did, moves within
+    // the fat boxes don't make the debug drawing rebuild its mesh
+    let mut changed = false;
+    // Despawned entities show up here as well
+    for entity in removed.iter() {
+        changed |= index.bypass_change_detection().remove(entity);
+    }
+    // The DynamicBVH only reinserts entities that left their fat box
+    for (entity, transform, aabb) in query.iter() {
+        changed |= index.bypass_change_detection().insert_or_update(entity, world_aabb(transform, aabb));
+    }
+    if changed {
+        index.set_changed();
+    }
+}
+
+// Read access to the SpatialIndex of all entities with the marker component M
+#[derive(SystemParam)]
+pub struct SpatialQuery<'w, 's, M: Component> {
+    index: Res<'w, SpatialIndex<M>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's, M: Component> SpatialQuery<'w, 's, M> {
+    pub fn nearest(&self, position: &Vec3) -> Option<(Entity, f32)> {
+        self.index.bvh.get_n_closest(position, 1).pop()
+    }
+
+    // The n closest entities, sorted by distance
+    pub fn n_nearest(&self, position: &Vec3, n: usize) -> Vec<(Entity, f32)> {
+        self.index.bvh.get_n_closest(position, n)
+    }
+
+    pub fn in_radius(&self, position: &Vec3, radius: f32) -> Vec<Entity> {
+        self.index.bvh.get_in_radius(position, radius)
+    }
+
+    // First entity hit by the ray, t is in multiples of direction
+    pub fn raycast(&self, origin: &Vec3, direction: &Vec3) -> Option<(Entity, f32)> {
+        self.index.bvh.raycast(origin, direction)
+    }
+}
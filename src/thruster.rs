@@ -1,4 +1,6 @@
-use crate::dynamics::RigidBody;
+use crate::collision_detection::AutoCollider;
+use crate::dynamics::{Ccd, RigidBody};
+use crate::inertia::box_inertia;
 use bevy::{
     prelude::*,
     input::{
@@ -127,11 +129,8 @@ fn spawn_test_thruster_3d(
     })
     .with(RigidBody::new(
          1.0,
-        Mat3::from_cols(
-            Vec3::new(1.0,5.0, 0.0),
-            Vec3::new(5.0, 1.0, 0.0),
-            Vec3::new(0.0, 0.0, 1.0),
-        ),
+        // Roughly the bounding box of the monkey
+        box_inertia(1.0, Vec3::new(1.4, 1.0, 0.85)),
         Vec3::zero(),
         Vec3::zero(),
         Vec3::zero(),
@@ -146,5 +145,8 @@ fn spawn_test_thruster_3d(
         torque_clockwise: Vec3::unit_y() * -1.0,
         torque_counter_clockwise: Vec3::unit_y() * 1.0,
     })
+    .with(AutoCollider::BoundingBox)
+    // Fast enough to tunnel through thin objects
+    .with(Ccd)
     ;
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use bevy::prelude::*;
+use crate::collision_detection::AutoCollider;
 // use bevy::log::info;
 
 pub struct TreePlugin;
@@ -37,7 +38,8 @@ fn spawn_tree_segment(
         },
         ..Default::default()
     })
-    .with(TreeSegment {_thickness: 1.0, children: Vec::new()});
+    .with(TreeSegment {_thickness: 1.0, children: Vec::new()})
+    .with(AutoCollider::BoundingBox);
     
     commands.current_entity().unwrap()
 }
@@ -2,7 +2,7 @@ use bevy::prelude::*;
 use rand::random;
 use rand::thread_rng;
 use rand_distr::{Distribution,Normal};
-use crate::collision_detection::{Collidable, BoundingBox};
+use crate::collision_detection::{Collidable, BoundingBox, ALL_LAYERS, SNOW_LAYER};
    
 pub struct WeatherPlugin;
 impl Plugin for WeatherPlugin {
@@ -94,11 +94,12 @@ fn spawn_snowflake(
             ..Default::default()
         })
         .with(SnowFlake)
+        // Snowflakes land on everything but don't collide with each other
         .with(Collidable::new(
             BoundingBox::new(
                 Vec3::splat(-radius),
                 Vec3::splat(radius),
             )
-        )
+        ).with_layers(SNOW_LAYER, ALL_LAYERS & !SNOW_LAYER)
     );
 }
\ No newline at end of file